@@ -0,0 +1,315 @@
+//! Bridge to MCP servers that run as Theater actors.
+//!
+//! A server is sent JSON-RPC messages with message server requests. Its tools are
+//! listed with `tools/list` and offered to the model as custom tools, and calls to them
+//! are sent with `tools/call`. The results are mapped to tool result content the
+//! Messages API accepts.
+
+use super::runner::{ToolCallReply, ToolCallRequest};
+use mcp_protocol::constants::methods::{INITIALIZE, TOOLS_CALL, TOOLS_LIST};
+use mcp_protocol::tool::{Tool, ToolCallParams, ToolCallResult, ToolContent, ToolsListParams};
+use mcp_protocol::JsonRpcMessage;
+use mcp_protocol::PROTOCOL_VERSION;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Most `tools/list` pages read from one server, in case its cursors never run out
+const MAX_LIST_PAGES: usize = 100;
+
+/// A `tools/list` page. Servers name the schema `inputSchema` as the MCP spec does, or
+/// `input_schema` as `mcp_protocol::tool::Tool` does, so both are read here.
+#[derive(Deserialize)]
+struct ToolsListPage {
+    tools: Vec<ListedTool>,
+
+    #[serde(default, rename = "nextCursor")]
+    next_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListedTool {
+    name: String,
+
+    #[serde(default)]
+    description: Option<String>,
+
+    #[serde(rename = "inputSchema", alias = "input_schema")]
+    input_schema: Value,
+}
+
+impl From<ListedTool> for Tool {
+    fn from(tool: ListedTool) -> Self {
+        Tool {
+            name: tool.name,
+            description: tool.description,
+            input_schema: tool.input_schema,
+            annotations: None,
+        }
+    }
+}
+
+/// Initialize an MCP server and list all of its tools
+pub fn list_tools<F>(actor_id: &str, send: &mut F) -> Result<Vec<Tool>, String>
+where
+    F: FnMut(&str, &[u8]) -> Result<Vec<u8>, String>,
+{
+    let initialize = json!({
+        "protocolVersion": PROTOCOL_VERSION,
+        "capabilities": {},
+        "clientInfo": { "name": "anthropic-proxy", "version": env!("CARGO_PKG_VERSION") }
+    });
+    call(actor_id, json!("initialize"), INITIALIZE, initialize, send)?;
+
+    let mut tools = Vec::new();
+    let mut cursor = None;
+    for page in 0..MAX_LIST_PAGES {
+        let params = serde_json::to_value(ToolsListParams { cursor })
+            .map_err(|e| format!("Failed to encode tools/list: {}", e))?;
+        let result = call(
+            actor_id,
+            json!(format!("tools-{}", page)),
+            TOOLS_LIST,
+            params,
+            send,
+        )?;
+        let page: ToolsListPage = serde_json::from_value(result)
+            .map_err(|e| format!("Invalid tools/list result: {}", e))?;
+
+        tools.extend(page.tools.into_iter().map(Tool::from));
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return Ok(tools),
+        }
+    }
+
+    Err(format!(
+        "tools/list still had more pages after {}",
+        MAX_LIST_PAGES
+    ))
+}
+
+/// Run a tool call with `tools/call` and map its result
+pub fn call_tool<F>(
+    actor_id: &str,
+    request: &ToolCallRequest,
+    send: &mut F,
+) -> Result<ToolCallReply, String>
+where
+    F: FnMut(&str, &[u8]) -> Result<Vec<u8>, String>,
+{
+    let params = serde_json::to_value(ToolCallParams {
+        name: request.name.clone(),
+        arguments: request.input.clone(),
+    })
+    .map_err(|e| format!("Failed to encode tools/call: {}", e))?;
+    let result = call(
+        actor_id,
+        json!(request.tool_use_id),
+        TOOLS_CALL,
+        params,
+        send,
+    )?;
+    let result: ToolCallResult =
+        serde_json::from_value(result).map_err(|e| format!("Invalid tools/call result: {}", e))?;
+
+    Ok(ToolCallReply {
+        content: result.content.into_iter().map(map_content).collect(),
+        is_error: result.is_error.unwrap_or(false),
+    })
+}
+
+/// Map an MCP content item to one the model can read.
+///
+/// Text and images are kept. Embedded text resources become their text, and content
+/// the model cannot read (audio and binary resources) is replaced by a note saying it
+/// was left out.
+fn map_content(content: ToolContent) -> ToolContent {
+    match content {
+        ToolContent::Resource { resource } => {
+            let uri = resource["uri"].as_str().unwrap_or("resource");
+            let text = match resource["text"].as_str() {
+                Some(text) => format!("{}\n{}", uri, text),
+                None => format!("[binary resource {} left out]", uri),
+            };
+            ToolContent::Text { text }
+        }
+        ToolContent::Audio { mime_type, .. } => ToolContent::Text {
+            text: format!("[{} audio left out]", mime_type),
+        },
+        content => content,
+    }
+}
+
+/// Send a JSON-RPC request and return its result
+fn call<F>(
+    actor_id: &str,
+    id: Value,
+    method: &str,
+    params: Value,
+    send: &mut F,
+) -> Result<Value, String>
+where
+    F: FnMut(&str, &[u8]) -> Result<Vec<u8>, String>,
+{
+    let message = serde_json::to_vec(&JsonRpcMessage::request(id, method, Some(params)))
+        .map_err(|e| format!("Failed to encode {}: {}", method, e))?;
+    let reply = send(actor_id, &message)?;
+
+    match serde_json::from_slice(&reply) {
+        Ok(JsonRpcMessage::Response {
+            error: Some(error), ..
+        }) => Err(format!(
+            "{} failed ({}): {}",
+            method, error.code, error.message
+        )),
+        Ok(JsonRpcMessage::Response {
+            result: Some(result),
+            ..
+        }) => Ok(result),
+        Ok(_) => Err(format!("{} got no result", method)),
+        Err(e) => Err(format!("Invalid JSON-RPC reply to {}: {}", method, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// An MCP server that answers from a table of results keyed by method, recording
+    /// every request it gets
+    fn server<'a>(
+        mut results: HashMap<&'static str, Vec<Value>>,
+        requests: &'a mut Vec<Value>,
+    ) -> impl FnMut(&str, &[u8]) -> Result<Vec<u8>, String> + 'a {
+        move |_, message| {
+            let request: Value = serde_json::from_slice(message).unwrap();
+            let method = request["method"].as_str().unwrap().to_string();
+            let id = request["id"].clone();
+            requests.push(request);
+
+            let reply = match results.get_mut(method.as_str()).filter(|r| !r.is_empty()) {
+                Some(queue) => JsonRpcMessage::response(id, queue.remove(0)),
+                None => JsonRpcMessage::error(id, -32601, "Method not found", None),
+            };
+            Ok(serde_json::to_vec(&reply).unwrap())
+        }
+    }
+
+    #[test]
+    fn tools_are_listed_across_pages() {
+        let mut requests = Vec::new();
+        let mut send = server(
+            HashMap::from([
+                (
+                    "initialize",
+                    vec![json!({ "protocolVersion": PROTOCOL_VERSION })],
+                ),
+                (
+                    "tools/list",
+                    vec![
+                        json!({
+                            "tools": [{
+                                "name": "echo",
+                                "description": "Echo the input",
+                                "inputSchema": { "type": "object", "properties": { "text": { "type": "string" } } },
+                                "annotations": { "readOnlyHint": true }
+                            }],
+                            "nextCursor": "page-2"
+                        }),
+                        json!({
+                            "tools": [{ "name": "add", "input_schema": { "type": "object" } }]
+                        }),
+                    ],
+                ),
+            ]),
+            &mut requests,
+        );
+
+        let tools = list_tools("mcp-actor", &mut send).unwrap();
+        drop(send);
+
+        let names: Vec<&str> = tools.iter().map(|tool| tool.name.as_str()).collect();
+        assert_eq!(names, ["echo", "add"]);
+        assert_eq!(tools[0].description.as_deref(), Some("Echo the input"));
+        assert_eq!(
+            tools[0].input_schema["properties"]["text"]["type"],
+            "string"
+        );
+        assert!(tools[0].annotations.is_none());
+        assert_eq!(tools[1].input_schema, json!({ "type": "object" }));
+
+        let methods: Vec<&str> = requests
+            .iter()
+            .map(|r| r["method"].as_str().unwrap())
+            .collect();
+        assert_eq!(methods, ["initialize", "tools/list", "tools/list"]);
+        assert_eq!(requests[1]["params"], json!({}));
+        assert_eq!(requests[2]["params"], json!({ "cursor": "page-2" }));
+    }
+
+    #[test]
+    fn listing_errors_are_reported() {
+        let mut requests = Vec::new();
+        let mut send = server(
+            HashMap::from([("initialize", vec![json!({})])]),
+            &mut requests,
+        );
+
+        assert_eq!(
+            list_tools("mcp-actor", &mut send).unwrap_err(),
+            "tools/list failed (-32601): Method not found"
+        );
+    }
+
+    #[test]
+    fn calls_are_sent_with_tools_call() {
+        let mut requests = Vec::new();
+        let mut send = server(
+            HashMap::from([(
+                "tools/call",
+                vec![json!({
+                    "content": [
+                        { "type": "text", "text": "3" },
+                        { "type": "image", "data": "aGk=", "mimeType": "image/png" },
+                        { "type": "resource", "resource": { "uri": "file:///notes.txt", "mimeType": "text/plain", "text": "Remember" } },
+                        { "type": "resource", "resource": { "uri": "file:///photo.raw", "blob": "AAAA" } },
+                        { "type": "audio", "data": "AAAA", "mimeType": "audio/wav" }
+                    ],
+                    "isError": true
+                })],
+            )]),
+            &mut requests,
+        );
+
+        let request = ToolCallRequest {
+            tool_use_id: "toolu_1".to_string(),
+            name: "add".to_string(),
+            input: json!({ "a": 1, "b": 2 }),
+        };
+        let reply = call_tool("mcp-actor", &request, &mut send).unwrap();
+        drop(send);
+
+        assert_eq!(
+            requests[0],
+            json!({
+                "jsonrpc": "2.0",
+                "id": "toolu_1",
+                "method": "tools/call",
+                "params": { "name": "add", "arguments": { "a": 1, "b": 2 } }
+            })
+        );
+
+        assert!(reply.is_error);
+        assert_eq!(
+            serde_json::to_value(&reply.content).unwrap(),
+            json!([
+                { "type": "text", "text": "3" },
+                { "type": "image", "data": "aGk=", "mimeType": "image/png" },
+                { "type": "text", "text": "file:///notes.txt\nRemember" },
+                { "type": "text", "text": "[binary resource file:///photo.raw left out]" },
+                { "type": "text", "text": "[audio/wav audio left out]" }
+            ])
+        );
+    }
+}
@@ -0,0 +1,2 @@
+pub mod mcp;
+pub mod runner;
@@ -0,0 +1,441 @@
+//! Running the tool calls of an agent turn on the actors that implement the tools.
+//!
+//! Each tool is routed to a Theater actor. A plain tool actor is sent a
+//! `ToolCallRequest` and answers with a `ToolCallReply`; an MCP server actor is sent
+//! `tools/call` (see [`super::mcp`]). Failures become error tool results, so the model
+//! sees them and the loop carries on.
+
+use super::mcp;
+use crate::schema::tool_input::{self, ToolInputError};
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicCompletionResponse, AnthropicCost, AnthropicError,
+    AnthropicMessage, AnthropicMessageContent, AnthropicUsage,
+};
+use mcp_protocol::tool::ToolContent;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Agent loop configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AgentConfig {
+    /// Maximum number of completions in one run, unless the request sets its own
+    pub max_iterations: u32,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self { max_iterations: 10 }
+    }
+}
+
+/// The actor a tool's calls are sent to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolRoute {
+    /// An actor that takes `ToolCallRequest`s
+    Actor(String),
+
+    /// An MCP server actor that takes `tools/call`
+    Mcp(String),
+}
+
+/// Message sent to a tool actor to run one tool call
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCallRequest {
+    /// ID of the tool_use block
+    pub tool_use_id: String,
+
+    /// Name of the tool that was called
+    pub name: String,
+
+    /// Input the model passed to the tool
+    pub input: Value,
+}
+
+/// A tool actor's answer to a `ToolCallRequest`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCallReply {
+    /// Output of the tool
+    pub content: Vec<ToolContent>,
+
+    /// Whether the tool failed
+    #[serde(default)]
+    pub is_error: bool,
+}
+
+/// Transcript and totals of an agent run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentRun {
+    /// The conversation: the request's messages, then every assistant turn and the
+    /// tool results sent back
+    pub messages: Vec<AnthropicMessage>,
+
+    /// The last completion
+    pub completion: AnthropicCompletionResponse,
+
+    /// Number of completions generated
+    pub iterations: u32,
+
+    /// Whether the run stopped at the iteration limit with tool calls left unanswered
+    pub iteration_limit_reached: bool,
+
+    /// Usage summed over every completion
+    pub usage: AnthropicUsage,
+
+    /// Cost summed over every completion
+    pub cost: AnthropicCost,
+}
+
+/// Route each tool to the actor mapped to it in `tool_actors`
+pub fn actor_routes(tool_actors: &HashMap<String, String>) -> HashMap<String, ToolRoute> {
+    tool_actors
+        .iter()
+        .map(|(name, actor_id)| (name.clone(), ToolRoute::Actor(actor_id.clone())))
+        .collect()
+}
+
+/// List the tools of each MCP server actor, add them to the request's tools and route
+/// their calls to the server.
+///
+/// A tool name that is already defined or routed is an error, since calls to it could
+/// not be told apart.
+pub fn add_mcp_tools<F>(
+    request: &mut AnthropicCompletionRequest,
+    routes: &mut HashMap<String, ToolRoute>,
+    mcp_actors: &[String],
+    send: &mut F,
+) -> Result<(), AnthropicError>
+where
+    F: FnMut(&str, &[u8]) -> Result<Vec<u8>, String>,
+{
+    for actor_id in mcp_actors {
+        let tools =
+            mcp::list_tools(actor_id, send).map_err(|message| AnthropicError::McpError {
+                actor_id: actor_id.clone(),
+                message,
+            })?;
+
+        let defined = request.tools.get_or_insert_with(Vec::new);
+        for tool in tools {
+            if routes.contains_key(&tool.name) || defined.iter().any(|t| t.name() == tool.name) {
+                return Err(AnthropicError::McpError {
+                    actor_id: actor_id.clone(),
+                    message: format!("tool '{}' is already defined", tool.name),
+                });
+            }
+            routes.insert(tool.name.clone(), ToolRoute::Mcp(actor_id.clone()));
+            defined.push(tool.into());
+        }
+    }
+    Ok(())
+}
+
+/// Run every tool call in a completion and build the turn that answers them.
+///
+/// `send` delivers a request to an actor and returns its reply. Calls with invalid
+/// input are answered with the violations instead of being run, and calls to a tool
+/// no actor implements are answered with an error.
+pub fn run_tool_calls<F>(
+    completion: &AnthropicCompletionResponse,
+    routes: &HashMap<String, ToolRoute>,
+    mut send: F,
+) -> AnthropicMessage
+where
+    F: FnMut(&str, &[u8]) -> Result<Vec<u8>, String>,
+{
+    let content = completion
+        .content
+        .iter()
+        .filter_map(|content| {
+            let AnthropicMessageContent::ToolUse { id, name, input } = content else {
+                return None;
+            };
+
+            let invalid = completion
+                .tool_input_errors
+                .iter()
+                .find(|error| &error.tool_use_id == id);
+            let request = ToolCallRequest {
+                tool_use_id: id.clone(),
+                name: name.clone(),
+                input: input.clone(),
+            };
+            let reply = match (invalid, routes.get(name)) {
+                (Some(error), _) => invalid_input(error),
+                (None, Some(ToolRoute::Actor(actor_id))) => {
+                    call_actor(actor_id, &request, &mut send)
+                }
+                (None, Some(ToolRoute::Mcp(actor_id))) => {
+                    mcp::call_tool(actor_id, &request, &mut send).unwrap_or_else(|e| {
+                        error_reply(format!("MCP actor {} failed: {}", actor_id, e))
+                    })
+                }
+                (None, None) => error_reply(format!("No actor implements the tool '{}'", name)),
+            };
+
+            Some(AnthropicMessageContent::ToolResult {
+                tool_use_id: id.clone(),
+                content: reply.content.into_iter().map(Into::into).collect(),
+                is_error: reply.is_error.then_some(true),
+            })
+        })
+        .collect();
+
+    AnthropicMessage {
+        role: "user".to_string(),
+        content,
+    }
+}
+
+/// Send one tool call to its actor
+fn call_actor<F>(actor_id: &str, request: &ToolCallRequest, send: &mut F) -> ToolCallReply
+where
+    F: FnMut(&str, &[u8]) -> Result<Vec<u8>, String>,
+{
+    let message = match serde_json::to_vec(request) {
+        Ok(message) => message,
+        Err(e) => return error_reply(format!("Failed to encode the tool call: {}", e)),
+    };
+
+    match send(actor_id, &message) {
+        Ok(reply) => serde_json::from_slice(&reply).unwrap_or_else(|e| {
+            error_reply(format!(
+                "Tool actor {} sent an invalid reply: {}",
+                actor_id, e
+            ))
+        }),
+        Err(e) => error_reply(format!("Tool actor {} failed: {}", actor_id, e)),
+    }
+}
+
+fn invalid_input(error: &ToolInputError) -> ToolCallReply {
+    error_reply(format!(
+        "{}\nThe tool was not run.",
+        tool_input::error_text(error)
+    ))
+}
+
+fn error_reply(text: String) -> ToolCallReply {
+    ToolCallReply {
+        content: vec![ToolContent::Text { text }],
+        is_error: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::validator::SchemaViolation;
+    use crate::types::api::AnthropicToolResultContent;
+    use serde_json::json;
+
+    fn completion() -> AnthropicCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5-20250929",
+            "content": [
+                { "type": "text", "text": "Let me check." },
+                { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Oslo" } },
+                { "type": "tool_use", "id": "toolu_2", "name": "calendar", "input": {} },
+                { "type": "tool_use", "id": "toolu_3", "name": "weather", "input": {} }
+            ],
+            "stop_reason": "tool_use",
+            "stop_sequence": null,
+            "usage": { "input_tokens": 100, "output_tokens": 40 }
+        }))
+        .unwrap()
+    }
+
+    fn result_text(content: &AnthropicMessageContent) -> (&str, &str, Option<bool>) {
+        let AnthropicMessageContent::ToolResult {
+            tool_use_id,
+            content,
+            is_error,
+        } = content
+        else {
+            panic!("expected a tool result");
+        };
+        let AnthropicToolResultContent::Tool(ToolContent::Text { text }) = &content[0] else {
+            panic!("expected text");
+        };
+        (tool_use_id, text, *is_error)
+    }
+
+    #[test]
+    fn every_call_is_answered() {
+        let mut completion = completion();
+        completion.tool_input_errors = vec![ToolInputError {
+            tool_use_id: "toolu_3".to_string(),
+            tool_name: "weather".to_string(),
+            violations: vec![SchemaViolation {
+                path: "/city".to_string(),
+                message: "is required".to_string(),
+            }],
+        }];
+        let tool_actors = HashMap::from([("weather".to_string(), "actor-weather".to_string())]);
+
+        let mut sent = Vec::new();
+        let message = run_tool_calls(
+            &completion,
+            &actor_routes(&tool_actors),
+            |actor_id, message| {
+                let request: ToolCallRequest = serde_json::from_slice(message).unwrap();
+                sent.push((actor_id.to_string(), request.tool_use_id, request.input));
+                Ok(br#"{ "content": [{ "type": "text", "text": "Sunny, 18C" }] }"#.to_vec())
+            },
+        );
+
+        // Only the valid call to a mapped tool reaches an actor
+        assert_eq!(
+            sent,
+            [(
+                "actor-weather".to_string(),
+                "toolu_1".to_string(),
+                json!({ "city": "Oslo" })
+            )]
+        );
+
+        assert_eq!(message.role, "user");
+        assert_eq!(message.content.len(), 3);
+        assert_eq!(
+            result_text(&message.content[0]),
+            ("toolu_1", "Sunny, 18C", None)
+        );
+
+        let (id, text, is_error) = result_text(&message.content[1]);
+        assert_eq!((id, is_error), ("toolu_2", Some(true)));
+        assert!(text.contains("No actor implements the tool 'calendar'"));
+
+        let (id, text, is_error) = result_text(&message.content[2]);
+        assert_eq!((id, is_error), ("toolu_3", Some(true)));
+        assert!(text.contains("/city: is required"));
+    }
+
+    #[test]
+    fn actor_failures_become_error_results() {
+        let tool_actors = HashMap::from([
+            ("weather".to_string(), "actor-weather".to_string()),
+            ("calendar".to_string(), "actor-calendar".to_string()),
+        ]);
+
+        let message =
+            run_tool_calls(
+                &completion(),
+                &actor_routes(&tool_actors),
+                |actor_id, _| match actor_id {
+                    "actor-weather" => Err("actor not found".to_string()),
+                    _ => Ok(b"not json".to_vec()),
+                },
+            );
+
+        let (_, text, is_error) = result_text(&message.content[0]);
+        assert_eq!(is_error, Some(true));
+        assert_eq!(text, "Tool actor actor-weather failed: actor not found");
+
+        let (_, text, is_error) = result_text(&message.content[1]);
+        assert_eq!(is_error, Some(true));
+        assert!(text.starts_with("Tool actor actor-calendar sent an invalid reply"));
+    }
+
+    #[test]
+    fn tool_errors_are_passed_on() {
+        let tool_actors = HashMap::from([("calendar".to_string(), "actor-calendar".to_string())]);
+        let mut completion = completion();
+        completion.content.retain(|content| {
+            matches!(content, AnthropicMessageContent::ToolUse { name, .. } if name == "calendar")
+        });
+
+        let message = run_tool_calls(&completion, &actor_routes(&tool_actors), |_, _| {
+            Ok(
+                br#"{ "content": [{ "type": "text", "text": "No access" }], "is_error": true }"#
+                    .to_vec(),
+            )
+        });
+        assert_eq!(
+            result_text(&message.content[0]),
+            ("toolu_2", "No access", Some(true))
+        );
+    }
+
+    /// An MCP server actor offering a `calendar` tool
+    fn mcp_server(actor_id: &str, message: &[u8]) -> Result<Vec<u8>, String> {
+        assert_eq!(actor_id, "actor-mcp");
+        let request: Value = serde_json::from_slice(message).unwrap();
+        let result = match request["method"].as_str().unwrap() {
+            "initialize" => json!({}),
+            "tools/list" => json!({
+                "tools": [{ "name": "calendar", "inputSchema": { "type": "object" } }]
+            }),
+            "tools/call" => json!({
+                "content": [{ "type": "text", "text": format!("Called with {}", request["params"]["arguments"]) }]
+            }),
+            method => panic!("unexpected {}", method),
+        };
+        Ok(
+            serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn mcp_tools_are_added_and_called() {
+        let mut request: AnthropicCompletionRequest = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [{ "role": "user", "content": [{ "type": "text", "text": "Plan my day" }] }],
+            "tools": [{ "name": "weather", "input_schema": { "type": "object" } }]
+        }))
+        .unwrap();
+        let tool_actors = HashMap::from([("weather".to_string(), "actor-weather".to_string())]);
+        let mut routes = actor_routes(&tool_actors);
+
+        add_mcp_tools(
+            &mut request,
+            &mut routes,
+            &["actor-mcp".to_string()],
+            &mut mcp_server,
+        )
+        .unwrap();
+
+        let names: Vec<&str> = request.tools.iter().flatten().map(|t| t.name()).collect();
+        assert_eq!(names, ["weather", "calendar"]);
+        assert_eq!(routes["calendar"], ToolRoute::Mcp("actor-mcp".to_string()));
+
+        let mut completion = completion();
+        completion.content.retain(|content| {
+            matches!(content, AnthropicMessageContent::ToolUse { name, .. } if name == "calendar")
+        });
+        let message = run_tool_calls(&completion, &routes, mcp_server);
+        assert_eq!(
+            result_text(&message.content[0]),
+            ("toolu_2", "Called with {}", None)
+        );
+    }
+
+    #[test]
+    fn mcp_tool_names_must_be_unique() {
+        let mut request: AnthropicCompletionRequest = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [{ "role": "user", "content": [{ "type": "text", "text": "Plan my day" }] }]
+        }))
+        .unwrap();
+        let tool_actors = HashMap::from([("calendar".to_string(), "actor-calendar".to_string())]);
+        let mut routes = actor_routes(&tool_actors);
+
+        let error = add_mcp_tools(
+            &mut request,
+            &mut routes,
+            &["actor-mcp".to_string()],
+            &mut mcp_server,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "MCP actor actor-mcp: tool 'calendar' is already defined"
+        );
+    }
+}
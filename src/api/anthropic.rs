@@ -1,10 +1,14 @@
 use crate::bindings::theater::simple::http_client::{send_http, HttpRequest};
 use crate::bindings::theater::simple::runtime::log;
 use crate::bindings::theater::simple::timing;
+use crate::context::caching::{self, PromptCachingConfig};
 use crate::types::api::{
     AnthropicCompletionRequest, AnthropicCompletionResponse, AnthropicError, AnthropicModelInfo,
 };
+use crate::types::circuit::{CircuitBreaker, CircuitBreakerConfig};
+use crate::types::rate_limit::{header_value, UpstreamRateLimits};
 use crate::types::state::RetryConfig;
+use crate::types::tools::{AnthropicTool, MCP_CLIENT_BETA};
 
 use serde_json::Value;
 
@@ -18,15 +22,27 @@ pub struct AnthropicClient {
 
     /// API version to use
     api_version: String,
+
+    /// Circuit breaker configuration for upstream failures
+    circuit_config: CircuitBreakerConfig,
+
+    /// Prompt caching configuration
+    prompt_caching: PromptCachingConfig,
 }
 
 impl AnthropicClient {
     /// Create a new Anthropic client
-    pub fn new(api_key: String) -> Self {
+    pub fn new(
+        api_key: String,
+        circuit_config: CircuitBreakerConfig,
+        prompt_caching: PromptCachingConfig,
+    ) -> Self {
         Self {
             api_key,
             base_url: "https://api.anthropic.com/v1".to_string(),
             api_version: "2023-06-01".to_string(),
+            circuit_config,
+            prompt_caching,
         }
     }
 
@@ -42,40 +58,79 @@ impl AnthropicClient {
         }
     }
 
+    /// Check if a status code indicates the upstream itself is failing
+    fn is_upstream_failure(status: u16) -> bool {
+        status >= 500
+    }
+
+    /// Check if a status code indicates a healthy upstream
+    fn is_success(status: u16) -> bool {
+        (200..300).contains(&status)
+    }
+
     /// Execute an HTTP request with exponential backoff retry logic
+    ///
+    /// Requests are rejected immediately while the circuit breaker is open. Once the
+    /// cooldown has elapsed a single probe attempt is made, without retries.
+    ///
+    /// The rate limit headers of every response are recorded into `rate_limits`, and a
+    /// `retry-after` header takes precedence over the backoff delay.
     fn execute_with_retry(
         &self,
         request: &HttpRequest,
         retry_config: &RetryConfig,
+        circuit: &mut CircuitBreaker,
+        mut rate_limits: Option<&mut UpstreamRateLimits>,
     ) -> Result<crate::bindings::theater::simple::http_client::HttpResponse, AnthropicError> {
         let start_time = timing::now();
         let mut current_delay = retry_config.initial_delay_ms;
         let mut attempt = 0;
 
+        if let Err(retry_after_ms) = circuit.check(start_time, &self.circuit_config) {
+            log(&format!(
+                "Circuit breaker open, rejecting request ({} ms remaining)",
+                retry_after_ms
+            ));
+            return Err(AnthropicError::CircuitOpen { retry_after_ms });
+        }
+
+        let max_retries = if circuit.is_half_open() {
+            log("Circuit breaker half-open, sending probe request");
+            0
+        } else {
+            retry_config.max_retries
+        };
+
         loop {
             attempt += 1;
             
-            log(&format!("HTTP request attempt {}/{}", attempt, retry_config.max_retries + 1));
+            log(&format!("HTTP request attempt {}/{}", attempt, max_retries + 1));
 
             // Send the request
             let response = match send_http(request) {
                 Ok(resp) => resp,
                 Err(e) => {
                     log(&format!("HTTP request failed: {}", e));
-                    if attempt > retry_config.max_retries {
+                    if circuit.record_failure(timing::now(), &self.circuit_config) {
+                        log("Circuit breaker opened");
                         return Err(AnthropicError::HttpError(e));
                     }
-                    
-                    // Check if we've exceeded the total timeout
-                    let elapsed = timing::now() - start_time;
-                    if elapsed >= retry_config.max_total_timeout_ms as u64 {
-                        log("Total retry timeout exceeded");
+
+                    if attempt > max_retries {
                         return Err(AnthropicError::HttpError(e));
                     }
                     
+                    // Give up if the wait would run past the total timeout
+                    let elapsed = timing::now().saturating_sub(start_time);
+                    let Some(delay) = Self::retry_delay(None, current_delay, retry_config, elapsed)
+                    else {
+                        log("Total retry timeout exceeded");
+                        return Err(AnthropicError::HttpError(e));
+                    };
+
                     // Wait before retrying
-                    log(&format!("Retrying after {} ms due to HTTP error", current_delay));
-                    let _ = timing::sleep(current_delay as u64);
+                    log(&format!("Retrying after {} ms due to HTTP error", delay));
+                    let _ = timing::sleep(delay);
                     current_delay = std::cmp::min(
                         (current_delay as f64 * retry_config.backoff_multiplier) as u32,
                         retry_config.max_delay_ms
@@ -84,6 +139,22 @@ impl AnthropicClient {
                 }
             };
 
+            // Record the rate limit headers
+            if let Some(limits) = rate_limits.as_deref_mut() {
+                limits.update(&response.headers, timing::now());
+            }
+
+            // Track upstream health for the circuit breaker. A 4xx, including 429, says
+            // nothing about whether the upstream is healthy, so it leaves the breaker as is.
+            if Self::is_upstream_failure(response.status) {
+                if circuit.record_failure(timing::now(), &self.circuit_config) {
+                    log(&format!("Circuit breaker opened after status {}", response.status));
+                    return Ok(response);
+                }
+            } else if Self::is_success(response.status) {
+                circuit.record_success();
+            }
+
             // Check if we got a successful response
             if response.status == 200 {
                 log(&format!("Request successful on attempt {}", attempt));
@@ -97,17 +168,22 @@ impl AnthropicClient {
             }
 
             // Check if we've exhausted our retries
-            if attempt > retry_config.max_retries {
-                log(&format!("Max retries ({}) exceeded", retry_config.max_retries));
+            if attempt > max_retries {
+                log(&format!("Max retries ({}) exceeded", max_retries));
                 return Ok(response);
             }
 
-            // Check if we've exceeded the total timeout
-            let elapsed = timing::now() - start_time;
-            if elapsed >= retry_config.max_total_timeout_ms as u64 {
+            // Wait before retrying, honouring retry-after when the server sends one, unless
+            // the wait would run past the total timeout
+            let retry_after_secs = header_value(&response.headers, "retry-after")
+                .and_then(|v| v.parse::<u64>().ok());
+            let elapsed = timing::now().saturating_sub(start_time);
+            let Some(delay) =
+                Self::retry_delay(retry_after_secs, current_delay, retry_config, elapsed)
+            else {
                 log("Total retry timeout exceeded");
                 return Ok(response);
-            }
+            };
 
             // Log the retry attempt
             let message = String::from_utf8_lossy(&response.body.unwrap_or_default()).to_string();
@@ -115,10 +191,8 @@ impl AnthropicClient {
                 "Retryable error {} on attempt {}: {}",
                 response.status, attempt, message
             ));
-            log(&format!("Retrying after {} ms", current_delay));
-
-            // Wait before retrying
-            let _ = timing::sleep(current_delay as u64);
+            log(&format!("Retrying after {} ms", delay));
+            let _ = timing::sleep(delay);
             
             // Update delay for next attempt (exponential backoff)
             current_delay = std::cmp::min(
@@ -128,8 +202,29 @@ impl AnthropicClient {
         }
     }
 
+    /// How long to wait before the next attempt, or `None` if the wait would reach the
+    /// end of the total retry timeout.
+    ///
+    /// A `retry-after` header takes precedence over the backoff delay. Both are capped
+    /// at `max_delay_ms`.
+    fn retry_delay(
+        retry_after_secs: Option<u64>,
+        backoff_ms: u32,
+        retry_config: &RetryConfig,
+        elapsed_ms: u64,
+    ) -> Option<u64> {
+        let delay = retry_after_secs
+            .map_or(backoff_ms as u64, |secs| secs.saturating_mul(1000))
+            .min(retry_config.max_delay_ms as u64);
+        let remaining = (retry_config.max_total_timeout_ms as u64).saturating_sub(elapsed_ms);
+        (delay < remaining).then_some(delay)
+    }
+
     /// List available models from the Anthropic API
-    pub fn list_models(&self) -> Result<Vec<AnthropicModelInfo>, AnthropicError> {
+    pub fn list_models(
+        &self,
+        circuit: &mut CircuitBreaker,
+    ) -> Result<Vec<AnthropicModelInfo>, AnthropicError> {
         log("Listing available Anthropic models");
 
         let request = HttpRequest {
@@ -152,7 +247,7 @@ impl AnthropicClient {
             max_total_timeout_ms: 15000,
         };
 
-        let response = self.execute_with_retry(&request, &retry_config)?;
+        let response = self.execute_with_retry(&request, &retry_config, circuit, None)?;
 
         // Check status code
         if response.status != 200 {
@@ -201,28 +296,156 @@ impl AnthropicClient {
         Ok(models)
     }
 
+    /// Count the input tokens of a request using the Anthropic token counting endpoint
+    pub fn count_tokens(
+        &self,
+        request: &AnthropicCompletionRequest,
+        circuit: &mut CircuitBreaker,
+    ) -> Result<u32, AnthropicError> {
+        log("Counting tokens with Anthropic API");
+
+        // The endpoint only accepts the fields that contribute to the prompt
+        let mut body = serde_json::json!({
+            "model": request.model,
+            "messages": request.messages,
+        });
+        if let Some(system) = &request.system {
+            body["system"] = serde_json::to_value(system)?;
+        }
+        if let Some(tools) = &request.tools {
+            body["tools"] = Self::wire_tools(tools)?;
+        }
+        if let Some(tool_choice) = request.wire_tool_choice() {
+            body["tool_choice"] = serde_json::to_value(tool_choice)?;
+        }
+
+        let mut headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("x-api-key".to_string(), self.api_key.clone()),
+            ("anthropic-version".to_string(), self.api_version.clone()),
+        ];
+        headers.extend(Self::beta_header(request));
+
+        let http_request = HttpRequest {
+            method: "POST".to_string(),
+            uri: format!("{}/messages/count_tokens", self.base_url),
+            headers,
+            body: Some(serde_json::to_vec(&body)?),
+        };
+
+        // Token counting is advisory, so keep retries light
+        let retry_config = RetryConfig {
+            max_retries: 1,
+            initial_delay_ms: 500,
+            max_delay_ms: 2000,
+            backoff_multiplier: 2.0,
+            max_total_timeout_ms: 5000,
+        };
+
+        let response = self.execute_with_retry(&http_request, &retry_config, circuit, None)?;
+
+        // Check status code
+        if response.status != 200 {
+            let message = String::from_utf8_lossy(&response.body.unwrap_or_default()).to_string();
+            return Err(AnthropicError::ApiError {
+                status: response.status,
+                message,
+            });
+        }
+
+        let body = response
+            .body
+            .ok_or_else(|| AnthropicError::InvalidResponse("No response body".to_string()))?;
+        let response_data: Value = serde_json::from_slice(&body)?;
+
+        response_data
+            .get("input_tokens")
+            .and_then(|v| v.as_u64())
+            .map(|tokens| tokens as u32)
+            .ok_or_else(|| AnthropicError::InvalidResponse("Missing input_tokens".to_string()))
+    }
+
+    /// The `anthropic-beta` header for the beta features a request uses, if any
+    fn beta_header(request: &AnthropicCompletionRequest) -> Option<(String, String)> {
+        let mut betas: Vec<&str> = Vec::new();
+        for tool in request.tools.iter().flatten() {
+            if let Some(beta) = tool.beta() {
+                if !betas.contains(&beta) {
+                    betas.push(beta);
+                }
+            }
+        }
+        if request.mcp_servers.as_ref().is_some_and(|servers| !servers.is_empty()) {
+            betas.push(MCP_CLIENT_BETA);
+        }
+
+        (!betas.is_empty()).then(|| ("anthropic-beta".to_string(), betas.join(",")))
+    }
+
+    /// Serialize tool definitions for the API.
+    ///
+    /// Custom tools are MCP tool definitions, whose `annotations` the API does not accept.
+    fn wire_tools(tools: &[AnthropicTool]) -> Result<Value, AnthropicError> {
+        let mut value = serde_json::to_value(tools)?;
+        for (tool, definition) in tools.iter().zip(value.as_array_mut().into_iter().flatten()) {
+            if let (AnthropicTool::Custom(_), Some(definition)) = (tool, definition.as_object_mut())
+            {
+                definition.remove("annotations");
+            }
+        }
+        Ok(value)
+    }
+
+    /// Build the JSON body for a Messages API request
+    fn request_body(&self, request: &AnthropicCompletionRequest) -> Result<Value, AnthropicError> {
+        let wire_request = AnthropicCompletionRequest {
+            tool_choice: request.wire_tool_choice(),
+            disable_parallel_tool_use: None,
+            ..request.clone()
+        };
+        let mut body = serde_json::to_value(&wire_request)?;
+        if let Some(tools) = &request.tools {
+            body["tools"] = Self::wire_tools(tools)?;
+        }
+
+        if self.prompt_caching.automatic {
+            let breakpoints = caching::plan_breakpoints(request);
+            caching::apply_breakpoints(&mut body, &breakpoints);
+        }
+
+        Ok(body)
+    }
+
     /// Generate a completion using the Anthropic API with retry logic
     pub fn generate_completion(
         &self,
         request: AnthropicCompletionRequest,
         retry_config: &RetryConfig,
+        circuit: &mut CircuitBreaker,
+        rate_limits: &mut UpstreamRateLimits,
     ) -> Result<AnthropicCompletionResponse, AnthropicError> {
         log("Generating completion with Anthropic API");
 
+        let body = self.request_body(&request)?;
+
         // Create the HTTP request
+        let mut headers = vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("x-api-key".to_string(), self.api_key.clone()),
+            ("anthropic-version".to_string(), "2023-06-01".to_string()),
+        ];
+        headers.extend(Self::beta_header(&request));
+
         let http_request = HttpRequest {
             method: "POST".to_string(),
             uri: format!("{}/messages", self.base_url),
-            headers: vec![
-                ("Content-Type".to_string(), "application/json".to_string()),
-                ("x-api-key".to_string(), self.api_key.clone()),
-                ("anthropic-version".to_string(), "2023-06-01".to_string()),
-            ],
-            body: Some(serde_json::to_vec(&request)?),
+            headers,
+            body: Some(serde_json::to_vec(&body)?),
         };
 
         // Execute with retry logic
-        let response = self.execute_with_retry(&http_request, retry_config)?;
+        let response =
+            self.execute_with_retry(&http_request, retry_config, circuit, Some(rate_limits))?;
 
         // Check status code
         if response.status != 200 {
@@ -240,6 +463,238 @@ impl AnthropicClient {
 
         log(&format!("Got response: {}", String::from_utf8_lossy(&body)));
 
-        serde_json::from_slice(&body).map_err(|e| AnthropicError::InvalidResponse(e.to_string()))
+        let mut completion: AnthropicCompletionResponse = serde_json::from_slice(&body)
+            .map_err(|e| AnthropicError::InvalidResponse(e.to_string()))?;
+        completion.upstream_request_id =
+            header_value(&response.headers, "request-id").map(|v| v.to_string());
+
+        Ok(completion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! Wire conformance: serialized requests must match captured, valid Messages API
+    //! request bodies in `src/api/wire`.
+
+    use super::*;
+    use crate::context::caching::PromptCachingConfig;
+    use crate::types::api::AnthropicToolChoice;
+    use serde_json::json;
+
+    const FIXTURES: &[(&str, &str)] = &[
+        ("text", include_str!("wire/text.json")),
+        (
+            "system_and_temperature",
+            include_str!("wire/system_and_temperature.json"),
+        ),
+        (
+            "tool_choice_any_disable_parallel",
+            include_str!("wire/tool_choice_any_disable_parallel.json"),
+        ),
+        ("tool_choice_tool", include_str!("wire/tool_choice_tool.json")),
+        (
+            "tool_use_round_trip",
+            include_str!("wire/tool_use_round_trip.json"),
+        ),
+        ("server_tools", include_str!("wire/server_tools.json")),
+        ("client_tools", include_str!("wire/client_tools.json")),
+        ("mcp_servers", include_str!("wire/mcp_servers.json")),
+        ("search_results", include_str!("wire/search_results.json")),
+    ];
+
+    fn client() -> AnthropicClient {
+        AnthropicClient::new(
+            "test-key".to_string(),
+            CircuitBreakerConfig::default(),
+            PromptCachingConfig::default(),
+        )
+    }
+
+    fn body(request: &AnthropicCompletionRequest) -> Value {
+        client().request_body(request).unwrap()
+    }
+
+    #[test]
+    fn retry_delays_stay_within_the_limits() {
+        // 30s max delay, 60s total
+        let config = RetryConfig::default();
+
+        assert_eq!(AnthropicClient::retry_delay(None, 2000, &config, 0), Some(2000));
+        assert_eq!(AnthropicClient::retry_delay(Some(5), 2000, &config, 0), Some(5000));
+
+        // retry-after is capped at max_delay_ms, even when it would overflow
+        assert_eq!(AnthropicClient::retry_delay(Some(120), 2000, &config, 0), Some(30_000));
+        assert_eq!(AnthropicClient::retry_delay(Some(u64::MAX), 2000, &config, 0), Some(30_000));
+
+        // A wait that reaches the total timeout returns the response instead
+        assert_eq!(AnthropicClient::retry_delay(Some(5), 2000, &config, 54_999), Some(5000));
+        assert_eq!(AnthropicClient::retry_delay(Some(5), 2000, &config, 55_000), None);
+        assert_eq!(AnthropicClient::retry_delay(None, 2000, &config, 70_000), None);
+    }
+
+    #[test]
+    fn only_2xx_and_5xx_move_the_circuit_breaker() {
+        assert!(AnthropicClient::is_success(200));
+        assert!(!AnthropicClient::is_upstream_failure(200));
+
+        // A rate-limited or rejected probe must neither close nor open the circuit
+        for status in [400, 401, 404, 413, 429] {
+            assert!(!AnthropicClient::is_success(status), "{}", status);
+            assert!(!AnthropicClient::is_upstream_failure(status), "{}", status);
+        }
+
+        assert!(AnthropicClient::is_upstream_failure(500));
+        assert!(AnthropicClient::is_upstream_failure(529));
+    }
+
+    #[test]
+    fn captured_bodies_round_trip() {
+        for (name, fixture) in FIXTURES {
+            let expected: Value = serde_json::from_str(fixture).unwrap();
+            let request: AnthropicCompletionRequest = serde_json::from_value(expected.clone())
+                .unwrap_or_else(|e| panic!("{}: {}", name, e));
+
+            assert_eq!(body(&request), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn server_tools_send_their_beta_headers() {
+        let request: AnthropicCompletionRequest =
+            serde_json::from_str(include_str!("wire/server_tools.json")).unwrap();
+
+        assert_eq!(
+            AnthropicClient::beta_header(&request),
+            Some((
+                "anthropic-beta".to_string(),
+                "web-fetch-2025-09-10,code-execution-2025-08-25".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn mcp_tool_annotations_are_not_sent() {
+        let expected: Value =
+            serde_json::from_str(include_str!("wire/tool_choice_tool.json")).unwrap();
+
+        // As listed by an MCP server's tools/list
+        let mut listed = expected.clone();
+        listed["tools"][0]["annotations"] = json!({ "readOnlyHint": true });
+        let request: AnthropicCompletionRequest = serde_json::from_value(listed).unwrap();
+
+        assert_eq!(body(&request), expected);
+    }
+
+    #[test]
+    fn client_tools_send_their_beta_headers() {
+        let request: AnthropicCompletionRequest =
+            serde_json::from_str(include_str!("wire/client_tools.json")).unwrap();
+
+        // Only computer use is still in beta; bash and the text editor are not
+        assert_eq!(
+            AnthropicClient::beta_header(&request),
+            Some((
+                "anthropic-beta".to_string(),
+                "computer-use-2025-01-24".to_string()
+            ))
+        );
+
+        let names: Vec<&str> = request
+            .tools
+            .iter()
+            .flatten()
+            .map(|tool| tool.name())
+            .collect();
+        assert_eq!(names, vec!["computer", "str_replace_based_edit_tool", "bash"]);
+    }
+
+    #[test]
+    fn mcp_servers_send_the_mcp_client_beta_header() {
+        let request: AnthropicCompletionRequest =
+            serde_json::from_str(include_str!("wire/mcp_servers.json")).unwrap();
+
+        assert_eq!(
+            AnthropicClient::beta_header(&request),
+            Some((
+                "anthropic-beta".to_string(),
+                "mcp-client-2025-04-04".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn captured_responses_round_trip() {
+        for fixture in [
+            include_str!("wire/server_tool_response.json"),
+            include_str!("wire/mcp_tool_response.json"),
+            include_str!("wire/search_result_response.json"),
+        ] {
+            let expected: Value = serde_json::from_str(fixture).unwrap();
+            let response: AnthropicCompletionResponse =
+                serde_json::from_value(expected.clone()).unwrap();
+
+            assert_eq!(serde_json::to_value(&response).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn top_level_disable_parallel_tool_use_moves_into_tool_choice() {
+        let fixture = include_str!("wire/tool_choice_any_disable_parallel.json");
+        let expected: Value = serde_json::from_str(fixture).unwrap();
+
+        // As sent by callers using the shared CompletionRequest type
+        let mut legacy = expected.clone();
+        legacy["tool_choice"] = json!({ "type": "any" });
+        legacy["disable_parallel_tool_use"] = json!(true);
+        let request: AnthropicCompletionRequest = serde_json::from_value(legacy).unwrap();
+
+        assert_eq!(body(&request), expected);
+    }
+
+    #[test]
+    fn disable_parallel_tool_use_without_tool_choice_uses_auto() {
+        let fixture = include_str!("wire/tool_choice_any_disable_parallel.json");
+        let mut legacy: Value = serde_json::from_str(fixture).unwrap();
+        legacy.as_object_mut().unwrap().remove("tool_choice");
+        legacy["disable_parallel_tool_use"] = json!(true);
+        let request: AnthropicCompletionRequest = serde_json::from_value(legacy).unwrap();
+
+        let body = body(&request);
+        assert_eq!(
+            body["tool_choice"],
+            json!({ "type": "auto", "disable_parallel_tool_use": true })
+        );
+        assert!(body.get("disable_parallel_tool_use").is_none());
+    }
+
+    #[test]
+    fn none_tool_choice_has_no_parallel_flag() {
+        let request = AnthropicCompletionRequest {
+            tool_choice: Some(AnthropicToolChoice::none()),
+            disable_parallel_tool_use: Some(true),
+            mcp_servers: None,
+            ..serde_json::from_str(include_str!("wire/tool_choice_tool.json")).unwrap()
+        };
+
+        assert_eq!(body(&request)["tool_choice"], json!({ "type": "none" }));
+    }
+
+    #[test]
+    fn automatic_cache_breakpoints() {
+        let system = "Pride and Prejudice is a novel of manners. ".repeat(200);
+        let fixture =
+            include_str!("wire/cached_system_prompt.json").replace("SYSTEM_PROMPT", &system);
+        let expected: Value = serde_json::from_str(&fixture).unwrap();
+
+        let mut plain = expected.clone();
+        plain["system"] = json!(system);
+        plain["messages"][0]["content"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("cache_control");
+        let request: AnthropicCompletionRequest = serde_json::from_value(plain).unwrap();
+
+        assert_eq!(body(&request), expected);
     }
 }
@@ -0,0 +1,343 @@
+//! Automatic prompt cache breakpoint placement.
+//!
+//! Breakpoints are chosen from the typed request, where prefix lengths can be
+//! estimated, and then written into the serialized body as `cache_control` markers.
+//! The typed tool and system fields cannot carry the marker themselves.
+
+use crate::tokens::estimator;
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicMessageContent, AnthropicModelInfo, AnthropicUsage,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Most cache breakpoints Anthropic accepts in one request
+const MAX_BREAKPOINTS: usize = 4;
+
+/// Prompt caching configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PromptCachingConfig {
+    /// Whether breakpoints are placed automatically
+    pub automatic: bool,
+}
+
+impl Default for PromptCachingConfig {
+    fn default() -> Self {
+        Self { automatic: true }
+    }
+}
+
+/// Where cache breakpoints go in a request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheBreakpoints {
+    /// On the last tool definition
+    pub tools: bool,
+
+    /// At the end of the system prompt
+    pub system: bool,
+
+    /// On the last content block of these messages
+    pub messages: Vec<usize>,
+}
+
+/// Choose cache breakpoints for a request.
+///
+/// Candidates in priority order are the end of the conversation (so the next turn
+/// can read it), the system prompt, the tool definitions and the end of the previous
+/// turn (so this request reads what the last one wrote). A candidate is skipped when
+/// the prefix it closes is shorter than the model's minimum cacheable length. At most
+/// four breakpoints are placed.
+pub fn plan_breakpoints(request: &AnthropicCompletionRequest) -> CacheBreakpoints {
+    let minimum = AnthropicModelInfo::get_min_cacheable_tokens(&request.model);
+    let prefix_tokens = |system: bool, messages: usize| {
+        estimator::estimate_request(&AnthropicCompletionRequest {
+            system: if system { request.system.clone() } else { None },
+            messages: request.messages[..messages].to_vec(),
+            ..request.clone()
+        })
+    };
+
+    let mut breakpoints = CacheBreakpoints::default();
+    let mut slots = MAX_BREAKPOINTS;
+
+    let last = request.messages.len().checked_sub(1);
+    if let Some(last) = last.filter(|&last| cacheable(request, last)) {
+        if prefix_tokens(true, last + 1) >= minimum {
+            breakpoints.messages.push(last);
+            slots -= 1;
+        }
+    }
+
+    if request.system.as_deref().is_some_and(|s| !s.is_empty())
+        && prefix_tokens(true, 0) >= minimum
+    {
+        breakpoints.system = true;
+        slots -= 1;
+    }
+
+    if request.tools.as_ref().is_some_and(|tools| !tools.is_empty())
+        && prefix_tokens(false, 0) >= minimum
+    {
+        breakpoints.tools = true;
+        slots -= 1;
+    }
+
+    // The previous turn ends just before the final user message
+    let previous = last
+        .and_then(|last| last.checked_sub(1))
+        .filter(|&previous| cacheable(request, previous));
+    if let Some(previous) = previous {
+        if slots > 0 && prefix_tokens(true, previous + 1) >= minimum {
+            breakpoints.messages.insert(0, previous);
+        }
+    }
+
+    breakpoints
+}
+
+/// Whether a message's last block can carry a breakpoint (empty text blocks cannot)
+fn cacheable(request: &AnthropicCompletionRequest, index: usize) -> bool {
+    match request.messages[index].content.last() {
+        Some(AnthropicMessageContent::Text { text, .. }) => !text.is_empty(),
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Write breakpoints into a serialized request body.
+///
+/// Breakpoints already present in the body are kept and count against the limit, so
+/// only as many new ones are added as there are free slots.
+pub fn apply_breakpoints(body: &mut Value, breakpoints: &CacheBreakpoints) {
+    let existing = count_markers(body);
+    let mut slots = MAX_BREAKPOINTS.saturating_sub(existing);
+    let marker = json!({ "type": "ephemeral" });
+
+    let mark = |block: &mut Value, slots: &mut usize| {
+        if *slots > 0 && block.get("cache_control").is_none() {
+            block["cache_control"] = marker.clone();
+            *slots -= 1;
+        }
+    };
+
+    // Later breakpoints cover more of the prompt, so they are placed first
+    for &index in breakpoints.messages.iter().rev() {
+        if let Some(block) = body["messages"][index]["content"]
+            .as_array_mut()
+            .and_then(|content| content.last_mut())
+        {
+            mark(block, &mut slots);
+        }
+    }
+
+    if breakpoints.system {
+        if let Some(text) = body["system"].as_str() {
+            body["system"] = json!([{ "type": "text", "text": text }]);
+        }
+        if let Some(block) = body["system"]
+            .as_array_mut()
+            .and_then(|system| system.last_mut())
+        {
+            mark(block, &mut slots);
+        }
+    }
+
+    if breakpoints.tools {
+        if let Some(tool) = body["tools"]
+            .as_array_mut()
+            .and_then(|tools| tools.last_mut())
+        {
+            mark(tool, &mut slots);
+        }
+    }
+}
+
+/// Count the `cache_control` markers already in a body.
+///
+/// Only the places a breakpoint can go are looked at: system blocks, tool definitions
+/// and message content blocks. A `cache_control` key inside a tool's `input_schema` or
+/// a `tool_use` input is data, not a breakpoint.
+fn count_markers(body: &Value) -> usize {
+    let content = blocks(&body["messages"])
+        .iter()
+        .flat_map(|message| blocks(&message["content"]));
+
+    blocks(&body["system"])
+        .iter()
+        .chain(blocks(&body["tools"]))
+        .chain(content)
+        .filter(|block| block.get("cache_control").is_some())
+        .count()
+}
+
+/// The items of a JSON array, or none if the value is not an array
+fn blocks(value: &Value) -> &[Value] {
+    value.as_array().map_or(&[], Vec::as_slice)
+}
+
+/// Prompt cache effectiveness for one model
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PromptCacheStats {
+    /// Completions recorded
+    pub requests: u64,
+
+    /// Completions that read from the cache
+    pub cache_hits: u64,
+
+    /// Uncached input tokens
+    pub input_tokens: u64,
+
+    /// Input tokens written to the cache
+    pub cache_creation_input_tokens: u64,
+
+    /// Input tokens read from the cache
+    pub cache_read_input_tokens: u64,
+
+    /// Fraction of all input tokens that were read from the cache
+    pub hit_rate: f64,
+}
+
+impl PromptCacheStats {
+    /// Add a completion's usage
+    pub fn record(&mut self, usage: &AnthropicUsage) {
+        let cache_read = usage.cache_read_input_tokens.unwrap_or(0) as u64;
+
+        self.requests += 1;
+        if cache_read > 0 {
+            self.cache_hits += 1;
+        }
+        self.input_tokens += usage.input_tokens as u64;
+        self.cache_creation_input_tokens += usage.cache_creation_input_tokens.unwrap_or(0) as u64;
+        self.cache_read_input_tokens += cache_read;
+
+        let total =
+            self.input_tokens + self.cache_creation_input_tokens + self.cache_read_input_tokens;
+        self.hit_rate = if total > 0 {
+            self.cache_read_input_tokens as f64 / total as f64
+        } else {
+            0.0
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A conversation whose first user message is long enough to cache on its own
+    fn request(model: &str) -> AnthropicCompletionRequest {
+        let long = "The quarterly report covers revenue, churn and hiring. ".repeat(100);
+        serde_json::from_value(json!({
+            "model": model,
+            "max_tokens": 1024,
+            "system": "Answer briefly.",
+            "tools": [{ "name": "search", "input_schema": { "type": "object" } }],
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": long }] },
+                { "role": "assistant", "content": [{ "type": "text", "text": "Understood." }] },
+                { "role": "user", "content": [{ "type": "text", "text": "What about churn?" }] }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn short_prefixes_are_skipped() {
+        // The system prompt and tools are far below the 1024-token minimum, so only the
+        // conversation gets breakpoints
+        assert_eq!(
+            plan_breakpoints(&request("claude-sonnet-4-5-20250929")),
+            CacheBreakpoints {
+                tools: false,
+                system: false,
+                messages: vec![1, 2],
+            }
+        );
+
+        // Haiku needs 2048 tokens, which the whole request does not reach
+        assert_eq!(
+            plan_breakpoints(&request("claude-3-5-haiku-20241022")),
+            CacheBreakpoints::default()
+        );
+    }
+
+    fn body() -> Value {
+        json!({
+            "system": "Answer briefly.",
+            "tools": [{ "name": "search", "input_schema": { "type": "object" } }],
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": "Hello" }] },
+                { "role": "assistant", "content": [{ "type": "text", "text": "Hi" }] },
+                { "role": "user", "content": [{ "type": "text", "text": "What about churn?" }] }
+            ]
+        })
+    }
+
+    fn all() -> CacheBreakpoints {
+        CacheBreakpoints {
+            tools: true,
+            system: true,
+            messages: vec![1, 2],
+        }
+    }
+
+    #[test]
+    fn at_most_four_breakpoints_counting_existing_ones() {
+        let mut body = body();
+        apply_breakpoints(&mut body, &all());
+        assert_eq!(count_markers(&body), 4);
+        assert_eq!(
+            body["system"][0]["cache_control"],
+            json!({ "type": "ephemeral" })
+        );
+        assert!(body["tools"][0].get("cache_control").is_some());
+
+        // With three markers already set, only the highest-priority new one is added
+        let mut body = self::body();
+        body["system"] = json!([{ "type": "text", "text": "Answer briefly.", "cache_control": { "type": "ephemeral" } }]);
+        body["tools"][0]["cache_control"] = json!({ "type": "ephemeral" });
+        body["messages"][0]["content"][0]["cache_control"] = json!({ "type": "ephemeral" });
+        apply_breakpoints(&mut body, &all());
+        assert_eq!(count_markers(&body), 4);
+        assert!(body["messages"][2]["content"][0]
+            .get("cache_control")
+            .is_some());
+        assert!(body["messages"][1]["content"][0]
+            .get("cache_control")
+            .is_none());
+    }
+
+    #[test]
+    fn caller_markers_are_kept() {
+        let mut body = body();
+        let caller_marker = json!({ "type": "ephemeral", "ttl": "1h" });
+        body["messages"][2]["content"][0]["cache_control"] = caller_marker.clone();
+
+        apply_breakpoints(&mut body, &all());
+        assert_eq!(
+            body["messages"][2]["content"][0]["cache_control"],
+            caller_marker
+        );
+        assert_eq!(count_markers(&body), 4);
+    }
+
+    #[test]
+    fn markers_in_schemas_and_inputs_do_not_count() {
+        let mut body = body();
+        body["tools"][0]["input_schema"] = json!({
+            "type": "object",
+            "properties": { "cache_control": { "type": "object" } }
+        });
+        body["messages"][1]["content"] = json!([{
+            "type": "tool_use",
+            "id": "toolu_1",
+            "name": "search",
+            "input": { "cache_control": { "type": "ephemeral" } }
+        }]);
+        assert_eq!(count_markers(&body), 0);
+
+        apply_breakpoints(&mut body, &all());
+        assert_eq!(count_markers(&body), 4);
+    }
+}
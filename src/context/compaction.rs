@@ -0,0 +1,511 @@
+//! Compacting long conversations by summarizing their older turns.
+//!
+//! Once a conversation grows past a threshold, everything except the most recent
+//! turns is summarized by a cheaper model and replaced with the summary. Summaries
+//! are cached by a hash of the conversation prefix they cover, so a long-running
+//! conversation is only re-summarized when it crosses the threshold again.
+
+use crate::context::window::starts_turn;
+use crate::tokens::estimator;
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicMessage, AnthropicMessageContent, AnthropicModelInfo,
+    AnthropicToolResultBlock, AnthropicToolResultContent,
+};
+use mcp_protocol::tool::ToolContent;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Instructions given to the summary model
+const SUMMARY_SYSTEM_PROMPT: &str = "You summarize the earlier part of a conversation between \
+a user and an AI assistant so the assistant can continue it without the full transcript. \
+Keep every fact, decision, open task, file name, identifier and tool outcome that later turns \
+may depend on. Write in the third person and do not add commentary.";
+
+/// Conversation compaction configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CompactionConfig {
+    /// Whether to compact long conversations at all
+    pub enabled: bool,
+
+    /// Fraction of the model's context window at which compaction kicks in
+    pub threshold: f64,
+
+    /// Number of most recent turns that are always kept verbatim
+    pub keep_recent_turns: usize,
+
+    /// Model used to write the summaries
+    pub summary_model: String,
+
+    /// Maximum tokens for a summary
+    pub max_summary_tokens: u32,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: 0.5,
+            keep_recent_turns: 4,
+            summary_model: "claude-3-5-haiku-20241022".to_string(),
+            max_summary_tokens: 2048,
+        }
+    }
+}
+
+/// A cached summary of a conversation prefix
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedSummary {
+    /// Summary text
+    pub summary: String,
+
+    /// Number of messages the summary covers
+    pub messages: usize,
+
+    /// When the summary was written, in milliseconds since the epoch
+    pub created_at: u64,
+}
+
+/// Summaries keyed by the hash of the conversation prefix they replace
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SummaryCache {
+    pub entries: HashMap<String, CachedSummary>,
+}
+
+impl SummaryCache {
+    /// Cache a summary, evicting the oldest entries beyond `max_entries`
+    pub fn insert(&mut self, key: String, summary: CachedSummary, max_entries: Option<usize>) {
+        self.entries.insert(key, summary);
+
+        if let Some(max_entries) = max_entries {
+            while self.entries.len() > max_entries {
+                let Some(oldest) = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.created_at)
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// What was done to compact a conversation
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactionReport {
+    /// Model that wrote the summary
+    pub summary_model: String,
+
+    /// Number of messages replaced by the summary
+    pub summarized_messages: usize,
+
+    /// Whether the summary came from the cache
+    pub cached: bool,
+
+    /// Estimated input tokens before compaction
+    pub original_input_tokens: u32,
+
+    /// Estimated input tokens after compaction
+    pub final_input_tokens: u32,
+}
+
+/// How a conversation should be compacted
+#[derive(Debug, Clone)]
+pub enum CompactionPlan {
+    /// A cached summary already covers the first `cut` messages
+    Reuse { cut: usize, summary: String },
+
+    /// The first `cut` messages need a new summary, written by sending `request`
+    Summarize {
+        cut: usize,
+        key: String,
+        request: AnthropicCompletionRequest,
+    },
+}
+
+/// Decide whether and how to compact a request.
+///
+/// Returns `None` if compaction is disabled, the conversation is under the threshold,
+/// or there are not enough turns beyond `keep_recent_turns` to summarize.
+pub fn plan(
+    request: &AnthropicCompletionRequest,
+    config: &CompactionConfig,
+    cache: &SummaryCache,
+) -> Option<CompactionPlan> {
+    if !config.enabled {
+        return None;
+    }
+
+    let context_window = AnthropicModelInfo::get_max_tokens(&request.model);
+    let threshold = (context_window as f64 * config.threshold) as u32;
+    if estimator::estimate_request(request) <= threshold {
+        return None;
+    }
+
+    // Cuts are only made before a fresh user turn, so tool_use/tool_result pairs stay together
+    let cut_points: Vec<usize> = (1..request.messages.len())
+        .filter(|&i| starts_turn(&request.messages[i]))
+        .collect();
+    let keep = config.keep_recent_turns.max(1);
+    if cut_points.len() < keep {
+        return None;
+    }
+    let cut_points = &cut_points[..=cut_points.len() - keep];
+    let target = *cut_points.last()?;
+
+    // Reuse the longest cached summary, as long as it brings the request under the threshold
+    let cached = cut_points.iter().rev().find_map(|&cut| {
+        let key = prefix_key(config, &request.messages[..cut]);
+        cache.entries.get(&key).map(|entry| (cut, entry.summary.clone()))
+    });
+
+    if let Some((cut, summary)) = &cached {
+        let mut compacted = request.clone();
+        apply(&mut compacted, *cut, summary);
+        if estimator::estimate_request(&compacted) <= threshold {
+            return Some(CompactionPlan::Reuse {
+                cut: *cut,
+                summary: summary.clone(),
+            });
+        }
+    }
+
+    // Otherwise summarize up to the target, building on the cached summary if there is one
+    let (previous, from) = match cached {
+        Some((cut, summary)) => (Some(summary), cut),
+        None => (None, 0),
+    };
+    let transcript = render_transcript(previous.as_deref(), &request.messages[from..target]);
+
+    Some(CompactionPlan::Summarize {
+        cut: target,
+        key: prefix_key(config, &request.messages[..target]),
+        request: AnthropicCompletionRequest {
+            model: config.summary_model.clone(),
+            messages: vec![AnthropicMessage {
+                role: "user".to_string(),
+                content: vec![AnthropicMessageContent::text(format!(
+                    "Summarize this conversation.\n\n<conversation>\n{}</conversation>",
+                    transcript
+                ))],
+            }],
+            max_tokens: config.max_summary_tokens,
+            temperature: Some(0.0),
+            system: Some(SUMMARY_SYSTEM_PROMPT.to_string()),
+            tools: None,
+            tool_choice: None,
+            disable_parallel_tool_use: None,
+            mcp_servers: None,
+        },
+    })
+}
+
+/// Replace the first `cut` messages of a request with a summary.
+///
+/// The summary is prepended to the first kept message, which always starts a user turn.
+pub fn apply(request: &mut AnthropicCompletionRequest, cut: usize, summary: &str) {
+    request.messages.drain(..cut);
+    if let Some(first) = request.messages.first_mut() {
+        first.content.insert(
+            0,
+            AnthropicMessageContent::text(format!(
+                "<conversation_summary>\nSummary of the earlier conversation:\n{}\n</conversation_summary>",
+                summary
+            )),
+        );
+    }
+}
+
+/// Cache key for a conversation prefix: an FNV-1a hash of the summary model and messages.
+///
+/// FNV is used rather than the std hasher because keys are persisted in the state and
+/// must stay stable across builds.
+fn prefix_key(config: &CompactionConfig, messages: &[AnthropicMessage]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+
+    feed(config.summary_model.as_bytes());
+    feed(&[0]);
+    feed(&serde_json::to_vec(messages).unwrap_or_default());
+
+    format!("{:016x}", hash)
+}
+
+/// Render messages as a plain-text transcript for the summary model
+fn render_transcript(previous: Option<&str>, messages: &[AnthropicMessage]) -> String {
+    let mut transcript = String::new();
+
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("Summary of the conversation so far:\n{}\n\n", previous));
+    }
+
+    for message in messages {
+        let speaker = if message.role == "assistant" {
+            "Assistant"
+        } else {
+            "User"
+        };
+
+        for content in &message.content {
+            let line = match content {
+                AnthropicMessageContent::Text { text, .. } => text.clone(),
+                AnthropicMessageContent::ToolUse { name, input, .. } => {
+                    format!("[called tool {} with {}]", name, input)
+                }
+                AnthropicMessageContent::ToolResult {
+                    content, is_error, ..
+                } => {
+                    let label = if *is_error == Some(true) {
+                        "tool error"
+                    } else {
+                        "tool result"
+                    };
+                    let parts: Vec<String> = content
+                        .iter()
+                        .map(|item| match item {
+                            AnthropicToolResultContent::Tool(item) => match item {
+                                ToolContent::Text { text } => text.clone(),
+                                ToolContent::Image { .. } => "[image]".to_string(),
+                                ToolContent::Audio { .. } => "[audio]".to_string(),
+                                ToolContent::Resource { .. } => "[resource]".to_string(),
+                            },
+                            AnthropicToolResultContent::Block(
+                                AnthropicToolResultBlock::SearchResult(result),
+                            ) => format!("[search result: {}]", result.to_text()),
+                        })
+                        .collect();
+                    format!("[{}: {}]", label, parts.join("\n"))
+                }
+                AnthropicMessageContent::SearchResult(result) => {
+                    format!("[search result: {}]", result.to_text())
+                }
+                AnthropicMessageContent::ServerToolUse { name, input, .. } => {
+                    format!("[used server tool {} with {}]", name, input)
+                }
+                AnthropicMessageContent::WebSearchToolResult { content, .. }
+                | AnthropicMessageContent::WebFetchToolResult { content, .. }
+                | AnthropicMessageContent::CodeExecutionToolResult { content, .. }
+                | AnthropicMessageContent::BashCodeExecutionToolResult { content, .. }
+                | AnthropicMessageContent::TextEditorCodeExecutionToolResult { content, .. } => {
+                    format!("[server tool result: {}]", content)
+                }
+                AnthropicMessageContent::McpToolUse {
+                    name,
+                    server_name,
+                    input,
+                    ..
+                } => {
+                    format!("[called {} on MCP server {} with {}]", name, server_name, input)
+                }
+                AnthropicMessageContent::McpToolResult { content, .. } => {
+                    format!("[MCP tool result: {}]", content)
+                }
+            };
+            transcript.push_str(&format!("{}: {}\n\n", speaker, line));
+        }
+    }
+
+    transcript
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// Three turns: the first two use a tool and carry long user messages, the last is short
+    fn request() -> AnthropicCompletionRequest {
+        let long = "The quarterly report covers revenue, churn and hiring. ".repeat(100);
+        let messages: Vec<Value> = vec![
+            json!({ "role": "user", "content": [{ "type": "text", "text": long }] }),
+            json!({ "role": "assistant", "content": [
+                { "type": "text", "text": "Let me look that up." },
+                { "type": "tool_use", "id": "toolu_1", "name": "search", "input": { "q": "revenue" } }
+            ] }),
+            json!({ "role": "user", "content": [
+                { "type": "tool_result", "tool_use_id": "toolu_1", "content": [{ "type": "text", "text": "Revenue grew 12%." }] }
+            ] }),
+            json!({ "role": "assistant", "content": [{ "type": "text", "text": "Revenue grew 12%." }] }),
+            json!({ "role": "user", "content": [{ "type": "text", "text": long }] }),
+            json!({ "role": "assistant", "content": [
+                { "type": "tool_use", "id": "toolu_2", "name": "search", "input": { "q": "churn" } }
+            ] }),
+            json!({ "role": "user", "content": [
+                { "type": "tool_result", "tool_use_id": "toolu_2", "content": [{ "type": "text", "text": "Churn fell to 3%." }] }
+            ] }),
+            json!({ "role": "assistant", "content": [{ "type": "text", "text": "Churn fell to 3%." }] }),
+            json!({ "role": "user", "content": [{ "type": "text", "text": "And hiring?" }] }),
+            json!({ "role": "assistant", "content": [{ "type": "text", "text": "Checking." }] }),
+        ];
+
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": messages
+        }))
+        .unwrap()
+    }
+
+    /// Compacts once the input passes 1,000 of the model's 200,000 tokens
+    fn config(keep_recent_turns: usize) -> CompactionConfig {
+        CompactionConfig {
+            enabled: true,
+            threshold: 0.005,
+            keep_recent_turns,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn short_conversations_are_left_alone() {
+        let cache = SummaryCache::default();
+        assert!(plan(&request(), &CompactionConfig::default(), &cache).is_none());
+
+        let enabled = CompactionConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(plan(&request(), &enabled, &cache).is_none());
+    }
+
+    #[test]
+    fn cuts_fall_on_turn_boundaries() {
+        let request = request();
+        let cache = SummaryCache::default();
+
+        for (keep, expected_cut) in [(1, 8), (2, 4)] {
+            let Some(CompactionPlan::Summarize {
+                cut,
+                key,
+                request: summary_request,
+            }) = plan(&request, &config(keep), &cache)
+            else {
+                panic!("expected a summary to be written");
+            };
+
+            // The kept messages start with a fresh user turn, never a tool_result
+            assert_eq!(cut, expected_cut);
+            assert!(starts_turn(&request.messages[cut]));
+            assert_eq!(key, prefix_key(&config(keep), &request.messages[..cut]));
+
+            let AnthropicMessageContent::Text { text, .. } =
+                &summary_request.messages[0].content[0]
+            else {
+                panic!("expected a text prompt");
+            };
+            assert!(text.contains("[called tool search with {\"q\":\"revenue\"}]"));
+            assert!(text.contains("[tool result: Revenue grew 12%.]"));
+            assert_eq!(text.contains("Churn fell to 3%."), cut == 8);
+            assert!(!text.contains("And hiring?"));
+            assert_eq!(summary_request.model, "claude-3-5-haiku-20241022");
+        }
+
+        // Too few turns beyond the ones to keep
+        assert!(plan(&request, &config(3), &cache).is_none());
+    }
+
+    #[test]
+    fn cached_summaries_are_reused() {
+        let request = request();
+        let config = config(1);
+        let mut cache = SummaryCache::default();
+        cache.insert(
+            prefix_key(&config, &request.messages[..8]),
+            CachedSummary {
+                summary: "Revenue grew 12% and churn fell to 3%.".to_string(),
+                messages: 8,
+                created_at: 1,
+            },
+            None,
+        );
+
+        let Some(CompactionPlan::Reuse { cut, summary }) = plan(&request, &config, &cache) else {
+            panic!("expected the cached summary to be reused");
+        };
+        assert_eq!(cut, 8);
+        assert_eq!(summary, "Revenue grew 12% and churn fell to 3%.");
+
+        // A different summary model does not share the cache
+        let other = CompactionConfig {
+            summary_model: "claude-3-haiku-20240307".to_string(),
+            ..config
+        };
+        assert!(matches!(
+            plan(&request, &other, &cache),
+            Some(CompactionPlan::Summarize { .. })
+        ));
+    }
+
+    #[test]
+    fn a_short_cached_summary_is_built_on() {
+        let request = request();
+        let config = config(1);
+        let mut cache = SummaryCache::default();
+        cache.insert(
+            prefix_key(&config, &request.messages[..4]),
+            CachedSummary {
+                summary: "Revenue grew 12%.".to_string(),
+                messages: 4,
+                created_at: 1,
+            },
+            None,
+        );
+
+        // The kept turns from 4 on are still over the threshold, so 4..8 is summarized
+        // along with the cached summary
+        let Some(CompactionPlan::Summarize {
+            cut,
+            request: summary_request,
+            ..
+        }) = plan(&request, &config, &cache)
+        else {
+            panic!("expected a new summary");
+        };
+        assert_eq!(cut, 8);
+        let AnthropicMessageContent::Text { text, .. } = &summary_request.messages[0].content[0]
+        else {
+            panic!("expected a text prompt");
+        };
+        assert!(text.contains("Summary of the conversation so far:\nRevenue grew 12%."));
+        assert!(!text.contains("[called tool search with {\"q\":\"revenue\"}]"));
+        assert!(text.contains("[called tool search with {\"q\":\"churn\"}]"));
+    }
+
+    #[test]
+    fn apply_replaces_the_prefix_with_one_summary() {
+        let mut request = request();
+        apply(&mut request, 8, "Revenue grew 12% and churn fell to 3%.");
+
+        assert_eq!(request.messages.len(), 2);
+        let summaries = request
+            .messages
+            .iter()
+            .flat_map(|message| &message.content)
+            .filter(|content| {
+                matches!(content, AnthropicMessageContent::Text { text, .. } if text.contains("<conversation_summary>"))
+            })
+            .count();
+        assert_eq!(summaries, 1);
+
+        let first = &request.messages[0];
+        assert_eq!(first.role, "user");
+        let AnthropicMessageContent::Text { text, .. } = &first.content[0] else {
+            panic!("expected the summary first");
+        };
+        assert_eq!(
+            text,
+            "<conversation_summary>\nSummary of the earlier conversation:\nRevenue grew 12% and churn fell to 3%.\n</conversation_summary>"
+        );
+        assert!(
+            matches!(&first.content[1], AnthropicMessageContent::Text { text, .. } if text == "And hiring?")
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Continuing turns the API paused or cut off before they were finished.
+//!
+//! Long server tool turns can stop with `pause_turn`, and long outputs with
+//! `max_tokens`. The partial assistant content is sent back as the last message, the
+//! model picks up where it left off, and the rounds are merged into one response.
+
+use crate::context::prefill;
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicCompletionResponse, AnthropicMessage,
+    AnthropicMessageContent,
+};
+use serde::{Deserialize, Serialize};
+
+/// Continuation configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ContinuationConfig {
+    /// Whether `pause_turn` responses are continued automatically
+    pub pause_turn: bool,
+
+    /// Maximum number of times one turn is continued after a pause
+    pub max_pause_turns: u32,
+
+    /// Whether text cut off by `max_tokens` is continued automatically
+    pub max_tokens: bool,
+
+    /// Maximum number of times one turn is continued after hitting `max_tokens`
+    pub max_tokens_rounds: u32,
+
+    /// Stop continuing once this many output tokens have been generated in total
+    pub max_total_output_tokens: Option<u32>,
+}
+
+impl Default for ContinuationConfig {
+    fn default() -> Self {
+        Self {
+            pause_turn: false,
+            max_pause_turns: 10,
+            max_tokens: false,
+            max_tokens_rounds: 3,
+            max_total_output_tokens: None,
+        }
+    }
+}
+
+/// How a response was assembled from several rounds
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ContinuationReport {
+    /// Rounds that ended with `pause_turn` and were continued
+    pub pause_turns: u32,
+
+    /// Rounds that ended with `max_tokens` and were continued
+    #[serde(default)]
+    pub max_tokens_rounds: u32,
+}
+
+/// Whether a response cut off by `max_tokens` can be continued by prefilling it.
+///
+/// Only text can be picked up mid-block; a truncated tool call cannot be completed.
+pub fn can_continue_text(partial: &AnthropicCompletionResponse) -> bool {
+    matches!(
+        partial.content.last(),
+        Some(AnthropicMessageContent::Text { .. })
+    )
+}
+
+/// The request that continues a partial response.
+///
+/// The partial content becomes the final assistant message, appended to one the
+/// request already ends with. The API rejects a final assistant message that ends in
+/// whitespace, so trailing whitespace is trimmed off it.
+pub fn continue_request(
+    request: &AnthropicCompletionRequest,
+    partial: &AnthropicCompletionResponse,
+) -> AnthropicCompletionRequest {
+    let mut request = request.clone();
+    match request.messages.last_mut() {
+        Some(last) if last.role == partial.role => {
+            last.content.extend(partial.content.iter().cloned())
+        }
+        _ => request.messages.push(AnthropicMessage {
+            role: partial.role.clone(),
+            content: partial.content.clone(),
+        }),
+    }
+    prefill::trim(&mut request);
+    request
+}
+
+/// Fold the next round into the response so far.
+///
+/// Content is concatenated, with text that continues a text block stitched onto it,
+/// and usage and cost are summed. The model continued from the block with its trailing
+/// whitespace trimmed, so that is what the continuation is stitched onto. The ID and stop reason are those of the last round.
+///
+/// Each round is a separate request, priced on its own input: pricing the summed usage
+/// would wrongly apply the long-context tier once the rounds add up past its threshold.
+pub fn merge(merged: &mut AnthropicCompletionResponse, next: AnthropicCompletionResponse) {
+    let mut content = next.content.into_iter().peekable();
+    if let (
+        Some(AnthropicMessageContent::Text {
+            text,
+            citations: None,
+        }),
+        Some(AnthropicMessageContent::Text {
+            citations: None, ..
+        }),
+    ) = (merged.content.last_mut(), content.peek())
+    {
+        if let Some(AnthropicMessageContent::Text { text: rest, .. }) = content.next() {
+            text.truncate(text.trim_end().len());
+            text.push_str(&rest);
+        }
+    }
+    merged.content.extend(content);
+    merged.usage.add(&next.usage);
+    merged.cost = match (merged.cost.take(), next.cost) {
+        (Some(mut cost), Some(next)) => {
+            cost.add(&next);
+            Some(cost)
+        }
+        (cost, next) => cost.or(next),
+    };
+    merged.id = next.id;
+    merged.stop_reason = next.stop_reason;
+    merged.stop_sequence = next.stop_sequence;
+    merged.upstream_request_id = next
+        .upstream_request_id
+        .or(merged.upstream_request_id.take());
+    merged.context_management = merged
+        .context_management
+        .take()
+        .or(next.context_management);
+    merged.compaction = merged.compaction.take().or(next.compaction);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::api::AnthropicModelInfo;
+    use serde_json::{json, Value};
+
+    fn response(id: &str, stop_reason: &str, content: Value) -> AnthropicCompletionResponse {
+        serde_json::from_value(json!({
+            "id": id,
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5-20250929",
+            "content": content,
+            "stop_reason": stop_reason,
+            "stop_sequence": null,
+            "usage": {
+                "input_tokens": 100,
+                "output_tokens": 20,
+                "server_tool_use": { "web_search_requests": 1 }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn paused_turn_is_continued_and_merged() {
+        let request: AnthropicCompletionRequest = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": "Research this" }] }
+            ]
+        }))
+        .unwrap();
+
+        let mut merged = response(
+            "msg_1",
+            "pause_turn",
+            json!([{
+                "type": "server_tool_use",
+                "id": "srvtoolu_1",
+                "name": "web_search",
+                "input": { "query": "a" }
+            }]),
+        );
+
+        let next = continue_request(&request, &merged);
+        assert_eq!(next.messages.len(), 2);
+        assert_eq!(next.messages[1].role, "assistant");
+
+        merge(
+            &mut merged,
+            response("msg_2", "end_turn", json!([{ "type": "text", "text": "Found it" }])),
+        );
+        assert_eq!(merged.id, "msg_2");
+        assert_eq!(merged.content.len(), 2);
+        assert_eq!(merged.usage.input_tokens, 200);
+        assert_eq!(merged.usage.output_tokens, 40);
+        let tools = merged.usage.server_tool_use.as_ref().unwrap();
+        assert_eq!(tools.web_search_requests, 2);
+
+        // Every round is continued from the original request with all content so far
+        let again = continue_request(&request, &merged);
+        assert_eq!(again.messages.len(), 2);
+        assert_eq!(again.messages[1].content.len(), 2);
+    }
+
+    #[test]
+    fn truncated_text_is_prefilled_and_stitched() {
+        let request: AnthropicCompletionRequest = serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": "Write a story" }] }
+            ]
+        }))
+        .unwrap();
+
+        let mut merged = response(
+            "msg_1",
+            "max_tokens",
+            json!([{ "type": "text", "text": "Once upon a time \n" }]),
+        );
+        assert!(can_continue_text(&merged));
+
+        let next = continue_request(&request, &merged);
+        let AnthropicMessageContent::Text { text, .. } = &next.messages[1].content[0] else {
+            panic!("expected the prefill to be text");
+        };
+        assert_eq!(text, "Once upon a time");
+
+        merge(
+            &mut merged,
+            response("msg_2", "end_turn", json!([{ "type": "text", "text": " there was a fox." }])),
+        );
+        assert_eq!(merged.content.len(), 1);
+        let AnthropicMessageContent::Text { text, .. } = &merged.content[0] else {
+            panic!("expected text");
+        };
+        assert_eq!(text, "Once upon a time there was a fox.");
+
+        let tool_use = response(
+            "msg_3",
+            "max_tokens",
+            json!([{ "type": "tool_use", "id": "toolu_1", "name": "write", "input": {} }]),
+        );
+        assert!(!can_continue_text(&tool_use));
+    }
+
+    #[test]
+    fn rounds_are_priced_separately() {
+        let pricing = AnthropicModelInfo::get_pricing("claude-sonnet-4-5-20250929");
+        let round = |id: &str, stop_reason: &str, text: &str| {
+            let mut round = response(id, stop_reason, json!([{ "type": "text", "text": text }]));
+            round.usage.input_tokens = 120_000;
+            round.usage.output_tokens = 1_000;
+            round.usage.server_tool_use = None;
+            round.cost = Some(pricing.cost(&round.usage));
+            round
+        };
+
+        let mut merged = round("msg_1", "max_tokens", "Once upon");
+        merge(&mut merged, round("msg_2", "end_turn", " a time"));
+
+        // 240k input in total, but neither request passed the 200k long-context threshold
+        assert_eq!(merged.usage.input_tokens, 240_000);
+        let cost = merged.cost.unwrap();
+        assert!(!cost.long_context);
+        // 2 * (120k * $3 + 1k * $15) per million
+        assert!((cost.input_usd - 0.72).abs() < 1e-9);
+        assert!((cost.output_usd - 0.03).abs() < 1e-9);
+        assert!((cost.total_usd - 0.75).abs() < 1e-9);
+        assert!(pricing.cost(&merged.usage).long_context);
+    }
+}
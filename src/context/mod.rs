@@ -0,0 +1,6 @@
+pub mod caching;
+pub mod compaction;
+pub mod continuation;
+pub mod normalize;
+pub mod prefill;
+pub mod window;
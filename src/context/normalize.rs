@@ -0,0 +1,320 @@
+//! Normalizing and repairing conversation histories before they are sent.
+//!
+//! Agents often build histories that the Messages API rejects with a 400 that does
+//! not say which message is at fault. Repairable problems are fixed here, and the rest
+//! are rejected with an error naming the message.
+
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicError, AnthropicMessage, AnthropicMessageContent,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// What to do with `system` role messages found in `messages`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SystemMessageHandling {
+    /// Fail the request
+    Reject,
+
+    /// Append their text to the system prompt
+    MoveToSystem,
+}
+
+/// Message normalization configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct NormalizationConfig {
+    /// Whether messages are normalized at all
+    pub enabled: bool,
+
+    /// What to do with `system` role messages
+    pub system_messages: SystemMessageHandling,
+}
+
+impl Default for NormalizationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            system_messages: SystemMessageHandling::MoveToSystem,
+        }
+    }
+}
+
+/// Normalize a request's messages, returning a description of each repair made.
+///
+/// In order:
+/// - `system` role messages are moved into the system prompt or rejected
+/// - empty and whitespace-only text blocks are dropped, then messages left empty
+/// - consecutive messages with the same role are merged
+/// - tool results are moved to the front of their user message
+/// - every tool result must answer a tool_use from an earlier assistant message
+pub fn normalize(
+    request: &mut AnthropicCompletionRequest,
+    config: &NormalizationConfig,
+) -> Result<Vec<String>, AnthropicError> {
+    let mut repairs = Vec::new();
+    if !config.enabled {
+        return Ok(repairs);
+    }
+
+    move_system_messages(request, config, &mut repairs)?;
+
+    for (index, message) in request.messages.iter().enumerate() {
+        if message.role != "user" && message.role != "assistant" {
+            return Err(AnthropicError::InvalidMessages(format!(
+                "message {} has role '{}', expected 'user' or 'assistant'",
+                index, message.role
+            )));
+        }
+    }
+
+    let mut dropped_blocks = 0;
+    for message in &mut request.messages {
+        let before = message.content.len();
+        message.content.retain(
+            |content| !matches!(content, AnthropicMessageContent::Text { text, .. } if text.trim().is_empty()),
+        );
+        dropped_blocks += before - message.content.len();
+    }
+    if dropped_blocks > 0 {
+        repairs.push(format!("dropped {} empty text blocks", dropped_blocks));
+    }
+
+    let before = request.messages.len();
+    request
+        .messages
+        .retain(|message| !message.content.is_empty());
+    if request.messages.len() < before {
+        repairs.push(format!(
+            "dropped {} empty messages",
+            before - request.messages.len()
+        ));
+    }
+
+    let before = request.messages.len();
+    let mut merged: Vec<AnthropicMessage> = Vec::with_capacity(before);
+    for message in request.messages.drain(..) {
+        match merged.last_mut() {
+            Some(last) if last.role == message.role => last.content.extend(message.content),
+            _ => merged.push(message),
+        }
+    }
+    request.messages = merged;
+    if request.messages.len() < before {
+        repairs.push(format!(
+            "merged {} consecutive same-role messages",
+            before - request.messages.len()
+        ));
+    }
+
+    let mut reordered = 0;
+    for message in request.messages.iter_mut().filter(|m| m.role == "user") {
+        let is_result =
+            |c: &AnthropicMessageContent| matches!(c, AnthropicMessageContent::ToolResult { .. });
+        let first_other = message.content.iter().position(|c| !is_result(c));
+        let misplaced =
+            first_other.is_some_and(|first| message.content[first..].iter().any(is_result));
+        if misplaced {
+            let (results, others): (Vec<_>, Vec<_>) =
+                message.content.drain(..).partition(|c| is_result(c));
+            message.content = results.into_iter().chain(others).collect();
+            reordered += 1;
+        }
+    }
+    if reordered > 0 {
+        repairs.push(format!(
+            "moved tool results to the front of {} user messages",
+            reordered
+        ));
+    }
+
+    check_tool_results(request)?;
+
+    Ok(repairs)
+}
+
+/// Move or reject `system` role messages
+fn move_system_messages(
+    request: &mut AnthropicCompletionRequest,
+    config: &NormalizationConfig,
+    repairs: &mut Vec<String>,
+) -> Result<(), AnthropicError> {
+    let Some(index) = request.messages.iter().position(|m| m.role == "system") else {
+        return Ok(());
+    };
+
+    if config.system_messages == SystemMessageHandling::Reject {
+        return Err(AnthropicError::InvalidMessages(format!(
+            "message {} has role 'system'; use the system prompt instead",
+            index
+        )));
+    }
+
+    let mut moved = 0;
+    let mut system: Vec<String> = request.system.take().into_iter().collect();
+    request.messages.retain(|message| {
+        if message.role != "system" {
+            return true;
+        }
+        for content in &message.content {
+            if let AnthropicMessageContent::Text { text, .. } = content {
+                system.push(text.clone());
+            }
+        }
+        moved += 1;
+        false
+    });
+
+    request.system = Some(system.join("\n\n")).filter(|s| !s.is_empty());
+    repairs.push(format!(
+        "moved {} system messages into the system prompt",
+        moved
+    ));
+    Ok(())
+}
+
+/// Check that every tool result answers a tool_use from an earlier assistant message
+fn check_tool_results(request: &AnthropicCompletionRequest) -> Result<(), AnthropicError> {
+    let mut tool_use_ids = HashSet::new();
+
+    for (index, message) in request.messages.iter().enumerate() {
+        for content in &message.content {
+            match content {
+                AnthropicMessageContent::ToolUse { id, .. } if message.role == "assistant" => {
+                    tool_use_ids.insert(id.as_str());
+                }
+                AnthropicMessageContent::ToolResult { tool_use_id, .. } => {
+                    if message.role != "user" {
+                        return Err(AnthropicError::InvalidMessages(format!(
+                            "message {} has role '{}' but contains a tool_result",
+                            index, message.role
+                        )));
+                    }
+                    if !tool_use_ids.contains(tool_use_id.as_str()) {
+                        return Err(AnthropicError::InvalidMessages(format!(
+                            "message {} has a tool_result for '{}', which no earlier tool_use has",
+                            index, tool_use_id
+                        )));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn request(messages: Value) -> AnthropicCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "system": "Be brief.",
+            "messages": messages
+        }))
+        .unwrap()
+    }
+
+    fn tool_use(id: &str) -> Value {
+        json!({ "type": "tool_use", "id": id, "name": "lookup", "input": {} })
+    }
+
+    fn tool_result(id: &str) -> Value {
+        json!({ "type": "tool_result", "tool_use_id": id, "content": [text("42")] })
+    }
+
+    fn text(text: &str) -> Value {
+        json!({ "type": "text", "text": text })
+    }
+
+    #[test]
+    fn repairs_a_malformed_history() {
+        let mut req = request(json!([
+            { "role": "system", "content": [text("Answer in French.")] },
+            { "role": "user", "content": [text("Look this up")] },
+            { "role": "user", "content": [text("  ")] },
+            { "role": "assistant", "content": [tool_use("toolu_1")] },
+            { "role": "user", "content": [text("Here it is"), tool_result("toolu_1")] },
+            { "role": "assistant", "content": [text("La réponse")] },
+            { "role": "assistant", "content": [text(" est ")] }
+        ]));
+
+        let repairs = normalize(&mut req, &NormalizationConfig::default()).unwrap();
+        assert_eq!(
+            repairs,
+            vec![
+                "moved 1 system messages into the system prompt",
+                "dropped 1 empty text blocks",
+                "dropped 1 empty messages",
+                "merged 1 consecutive same-role messages",
+                "moved tool results to the front of 1 user messages",
+            ]
+        );
+
+        assert_eq!(req.system.as_deref(), Some("Be brief.\n\nAnswer in French."));
+        let roles: Vec<&str> = req.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user", "assistant"]);
+        assert!(matches!(
+            &req.messages[2].content[0],
+            AnthropicMessageContent::ToolResult { .. }
+        ));
+        // Merged prefill blocks are kept as sent; trimming is left to the caller
+        assert_eq!(req.messages[3].content.len(), 2);
+
+        // A normalized history needs no further repairs
+        assert!(normalize(&mut req, &NormalizationConfig::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_what_cannot_be_repaired() {
+        let reject_system = NormalizationConfig {
+            enabled: true,
+            system_messages: SystemMessageHandling::Reject,
+        };
+        let mut req = request(json!([
+            { "role": "system", "content": [text("Answer in French.")] },
+            { "role": "user", "content": [text("Hello")] }
+        ]));
+        assert!(matches!(
+            normalize(&mut req, &reject_system),
+            Err(AnthropicError::InvalidMessages(_))
+        ));
+
+        let mut req = request(json!([
+            { "role": "user", "content": [text("Hello")] },
+            { "role": "tool", "content": [text("42")] }
+        ]));
+        assert!(normalize(&mut req, &NormalizationConfig::default()).is_err());
+
+        let mut req = request(json!([
+            { "role": "user", "content": [tool_result("toolu_missing")] }
+        ]));
+        let Err(AnthropicError::InvalidMessages(message)) =
+            normalize(&mut req, &NormalizationConfig::default())
+        else {
+            panic!("expected an orphaned tool result to be rejected");
+        };
+        assert!(message.contains("toolu_missing"));
+    }
+
+    #[test]
+    fn disabled_normalization_leaves_the_request_alone() {
+        let disabled = NormalizationConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        let mut req = request(json!([
+            { "role": "user", "content": [text("Hello")] },
+            { "role": "user", "content": [text("  ")] }
+        ]));
+        assert!(normalize(&mut req, &disabled).unwrap().is_empty());
+        assert_eq!(req.messages.len(), 2);
+    }
+}
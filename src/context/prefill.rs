@@ -0,0 +1,127 @@
+//! Assistant prefill: a request ending with a partial assistant turn that the model
+//! continues from.
+
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicCompletionResponse, AnthropicMessageContent,
+};
+use serde::{Deserialize, Serialize};
+
+/// Prefill configuration
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct PrefillConfig {
+    /// Whether the prefill is prepended to the returned text, so the response holds
+    /// the complete assistant turn
+    pub prepend_to_response: bool,
+}
+
+/// The prefill a request ends with, if any.
+///
+/// This is the text after the last non-text block of a final assistant message, with
+/// trailing whitespace trimmed as it is before sending.
+pub fn text(request: &AnthropicCompletionRequest) -> Option<String> {
+    let last = request.messages.last().filter(|m| m.role == "assistant")?;
+    let mut parts: Vec<&str> = last
+        .content
+        .iter()
+        .rev()
+        .map_while(|content| match content {
+            AnthropicMessageContent::Text { text, .. } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+    parts.reverse();
+
+    let prefill = parts.concat().trim_end().to_string();
+    (!prefill.is_empty()).then_some(prefill)
+}
+
+/// Trim trailing whitespace off a final assistant message, which the API rejects.
+///
+/// Text blocks left empty are dropped. Returns whether anything changed.
+pub fn trim(request: &mut AnthropicCompletionRequest) -> bool {
+    let Some(last) = request.messages.last_mut().filter(|m| m.role == "assistant") else {
+        return false;
+    };
+
+    let mut trimmed = false;
+    while let Some(AnthropicMessageContent::Text { text, .. }) = last.content.last_mut() {
+        let len = text.trim_end().len();
+        if len == text.len() {
+            break;
+        }
+        trimmed = true;
+        if len > 0 {
+            text.truncate(len);
+            break;
+        }
+        last.content.pop();
+    }
+    trimmed
+}
+
+/// Put the prefill in front of the generated text
+pub fn prepend(completion: &mut AnthropicCompletionResponse, prefill: &str) {
+    match completion.content.first_mut() {
+        Some(AnthropicMessageContent::Text { text, .. }) => text.insert_str(0, prefill),
+        _ => completion
+            .content
+            .insert(0, AnthropicMessageContent::text(prefill)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(assistant: serde_json::Value) -> AnthropicCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": "List three colors as JSON" }] },
+                { "role": "assistant", "content": assistant }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn trailing_whitespace_is_trimmed() {
+        let mut req = request(json!([
+            { "type": "text", "text": "Here you go: " },
+            { "type": "text", "text": "{\n" },
+            { "type": "text", "text": "  \n" }
+        ]));
+        assert_eq!(text(&req).as_deref(), Some("Here you go: {"));
+
+        assert!(trim(&mut req));
+        let content = &req.messages[1].content;
+        assert_eq!(content.len(), 2);
+        assert!(matches!(&content[1], AnthropicMessageContent::Text { text, .. } if text == "{"));
+        assert!(!trim(&mut req));
+    }
+
+    #[test]
+    fn prefill_is_prepended_to_the_response() {
+        let req = request(json!([{ "type": "text", "text": "[\"" }]));
+        let mut completion: AnthropicCompletionResponse = serde_json::from_value(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5-20250929",
+            "content": [{ "type": "text", "text": "red\", \"green\", \"blue\"]" }],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": { "input_tokens": 20, "output_tokens": 10 }
+        }))
+        .unwrap();
+
+        prepend(&mut completion, &text(&req).unwrap());
+        assert!(matches!(
+            &completion.content[0],
+            AnthropicMessageContent::Text { text, .. } if text == "[\"red\", \"green\", \"blue\"]"
+        ));
+    }
+}
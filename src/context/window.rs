@@ -0,0 +1,452 @@
+//! Fitting requests into the model's context window.
+//!
+//! Requests are measured with the offline estimator before they are sent, so an
+//! oversized conversation fails fast (or is trimmed) instead of being uploaded
+//! only to be rejected with a 400.
+
+use crate::tokens::estimator;
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicError, AnthropicMessage, AnthropicMessageContent,
+    AnthropicModelInfo, AnthropicToolResultContent,
+};
+use mcp_protocol::tool::ToolContent;
+use serde::{Deserialize, Serialize};
+
+/// Tokens a truncated tool result text is never cut below
+const MIN_TOOL_RESULT_TOKENS: u32 = 64;
+
+/// What to do when a request does not fit in the context window
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ContextStrategy {
+    /// Fail with an error describing the overflow
+    Reject,
+
+    /// Drop the oldest turns, never separating a tool_use from its tool_result
+    DropOldest,
+
+    /// Shorten the largest tool result texts
+    TruncateToolResults,
+}
+
+/// Context window management configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ContextWindowConfig {
+    /// Strategy for requests that do not fit
+    pub strategy: ContextStrategy,
+
+    /// Fraction of the window held back to absorb estimation error
+    pub safety_margin: f64,
+}
+
+impl Default for ContextWindowConfig {
+    fn default() -> Self {
+        Self {
+            strategy: ContextStrategy::Reject,
+            safety_margin: 0.05,
+        }
+    }
+}
+
+/// What was done to fit a request into the context window
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContextManagementReport {
+    /// Strategy that was applied
+    pub strategy: ContextStrategy,
+
+    /// Estimated input tokens before trimming
+    pub original_input_tokens: u32,
+
+    /// Estimated input tokens after trimming
+    pub final_input_tokens: u32,
+
+    /// Number of messages dropped from the start of the conversation
+    pub dropped_messages: usize,
+
+    /// Number of tool result texts that were shortened
+    pub truncated_tool_results: usize,
+}
+
+/// Tokens of a model's context window left for input and output once the safety
+/// margin is held back
+pub fn usable_tokens(model: &str, config: &ContextWindowConfig) -> u32 {
+    let context_window = AnthropicModelInfo::get_max_tokens(model);
+    (context_window as f64 * (1.0 - config.safety_margin)) as u32
+}
+
+/// Whether `input_tokens` plus the request's `max_tokens` fit in the usable window
+pub fn fits(
+    request: &AnthropicCompletionRequest,
+    input_tokens: u32,
+    config: &ContextWindowConfig,
+) -> bool {
+    input_tokens as u64 + request.max_tokens as u64 <= usable_tokens(&request.model, config) as u64
+}
+
+/// Make sure a request fits in its model's context window.
+///
+/// Returns `None` if the request already fits, or a report of what was changed.
+pub fn fit_to_window(
+    request: &mut AnthropicCompletionRequest,
+    config: &ContextWindowConfig,
+) -> Result<Option<ContextManagementReport>, AnthropicError> {
+    let context_window = AnthropicModelInfo::get_max_tokens(&request.model);
+    let usable = usable_tokens(&request.model, config);
+    let budget = usable.saturating_sub(request.max_tokens);
+
+    let original_input_tokens = estimator::estimate_request(request);
+    if fits(request, original_input_tokens, config) {
+        return Ok(None);
+    }
+
+    let mut report = ContextManagementReport {
+        strategy: config.strategy,
+        original_input_tokens,
+        final_input_tokens: original_input_tokens,
+        dropped_messages: 0,
+        truncated_tool_results: 0,
+    };
+
+    match config.strategy {
+        ContextStrategy::Reject => {}
+        ContextStrategy::DropOldest => {
+            report.dropped_messages = drop_oldest(request, budget);
+        }
+        ContextStrategy::TruncateToolResults => {
+            report.truncated_tool_results = truncate_tool_results(request, budget);
+        }
+    }
+
+    report.final_input_tokens = estimator::estimate_request(request);
+    if !fits(request, report.final_input_tokens, config) {
+        return Err(AnthropicError::ContextWindowExceeded {
+            model: request.model.clone(),
+            input_tokens: report.final_input_tokens,
+            max_tokens: request.max_tokens,
+            usable_tokens: usable,
+            context_window,
+        });
+    }
+
+    Ok(Some(report))
+}
+
+/// Whether a message starts a new user turn, rather than carrying tool results
+pub fn starts_turn(message: &AnthropicMessage) -> bool {
+    message.role == "user"
+        && !message
+            .content
+            .iter()
+            .any(|c| matches!(c, AnthropicMessageContent::ToolResult { .. }))
+}
+
+/// Drop whole turns from the start until the request fits, returning the number of messages dropped.
+///
+/// Cuts are only made before a user message without tool results, so a tool_use and
+/// its tool_result are always kept or dropped together. The last turn is never dropped.
+fn drop_oldest(request: &mut AnthropicCompletionRequest, budget: u32) -> usize {
+    let cut_points: Vec<usize> = (1..request.messages.len())
+        .filter(|&i| starts_turn(&request.messages[i]))
+        .collect();
+
+    let fixed = estimator::estimate_request(&AnthropicCompletionRequest {
+        messages: Vec::new(),
+        ..request.clone()
+    });
+    let message_tokens: Vec<u32> = request
+        .messages
+        .iter()
+        .map(|message| {
+            estimator::estimate_request(&AnthropicCompletionRequest {
+                messages: vec![message.clone()],
+                ..request.clone()
+            }) - fixed
+        })
+        .collect();
+
+    let mut remaining: u32 = fixed + message_tokens.iter().sum::<u32>();
+    let mut dropped = 0;
+
+    for cut in cut_points {
+        if remaining <= budget {
+            break;
+        }
+        remaining -= message_tokens[dropped..cut].iter().sum::<u32>();
+        dropped = cut;
+    }
+
+    request.messages.drain(..dropped);
+    dropped
+}
+
+/// Shorten the largest tool result texts until the request fits, returning how many were shortened
+fn truncate_tool_results(request: &mut AnthropicCompletionRequest, budget: u32) -> usize {
+    let mut truncated = Vec::new();
+
+    loop {
+        let total = estimator::estimate_request(request);
+        if total <= budget {
+            break;
+        }
+        let excess = total - budget;
+
+        // Find the largest tool result text that has not been shortened yet
+        let mut largest: Option<(usize, usize, usize, u32)> = None;
+        for (m, message) in request.messages.iter().enumerate() {
+            for (c, content) in message.content.iter().enumerate() {
+                let AnthropicMessageContent::ToolResult { content: items, .. } = content else {
+                    continue;
+                };
+                for (i, item) in items.iter().enumerate() {
+                    let AnthropicToolResultContent::Tool(ToolContent::Text { text }) = item else {
+                        continue;
+                    };
+                    if truncated.contains(&(m, c, i)) {
+                        continue;
+                    }
+                    let tokens = estimator::estimate_text(text);
+                    if tokens > MIN_TOOL_RESULT_TOKENS
+                        && largest.is_none_or(|(.., largest)| tokens > largest)
+                    {
+                        largest = Some((m, c, i, tokens));
+                    }
+                }
+            }
+        }
+
+        let Some((m, c, i, tokens)) = largest else {
+            break;
+        };
+        let AnthropicMessageContent::ToolResult { content: items, .. } =
+            &mut request.messages[m].content[c]
+        else {
+            break;
+        };
+        let AnthropicToolResultContent::Tool(ToolContent::Text { text }) = &mut items[i] else {
+            break;
+        };
+
+        // Keep the longest prefix that, with the truncation marker, sheds the excess
+        let target = tokens.saturating_sub(excess).max(MIN_TOOL_RESULT_TOKENS);
+        let chars: Vec<char> = text.chars().collect();
+        let shorten = |keep: usize| {
+            let mut shortened: String = chars[..keep].iter().collect();
+            shortened.push_str(&format!(
+                "\n[... truncated {} characters to fit the context window ...]",
+                chars.len() - keep
+            ));
+            shortened
+        };
+
+        let (mut low, mut high) = (0, chars.len());
+        while low < high {
+            let mid = (low + high).div_ceil(2);
+            if estimator::estimate_text(&shorten(mid)) <= target {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        let shortened = shorten(low);
+
+        *text = shortened;
+        truncated.push((m, c, i));
+    }
+
+    truncated.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// Leaves 4000 tokens for the input: 95% of the 200k window minus `max_tokens`
+    const MAX_TOKENS: u32 = 186_000;
+    const BUDGET: u32 = 4_000;
+
+    fn request(messages: Value) -> AnthropicCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": MAX_TOKENS,
+            "messages": messages
+        }))
+        .unwrap()
+    }
+
+    fn config(strategy: ContextStrategy) -> ContextWindowConfig {
+        ContextWindowConfig {
+            strategy,
+            ..Default::default()
+        }
+    }
+
+    /// About `tokens` tokens of prose
+    fn prose(tokens: usize) -> String {
+        "the quick brown fox jumps over the lazy dog ".repeat(tokens / 9)
+    }
+
+    fn user(text: &str) -> Value {
+        json!({ "role": "user", "content": [{ "type": "text", "text": text }] })
+    }
+
+    fn assistant(text: &str) -> Value {
+        json!({ "role": "assistant", "content": [{ "type": "text", "text": text }] })
+    }
+
+    fn tool_use(id: &str) -> Value {
+        json!({
+            "role": "assistant",
+            "content": [{ "type": "tool_use", "id": id, "name": "read_file", "input": {} }]
+        })
+    }
+
+    fn tool_result(id: &str, text: &str) -> Value {
+        json!({
+            "role": "user",
+            "content": [{
+                "type": "tool_result",
+                "tool_use_id": id,
+                "content": [{ "type": "text", "text": text }]
+            }]
+        })
+    }
+
+    fn first_text(message: &AnthropicMessage) -> &str {
+        match &message.content[0] {
+            AnthropicMessageContent::Text { text, .. } => text,
+            _ => panic!("expected text"),
+        }
+    }
+
+    #[test]
+    fn requests_that_fit_are_left_alone() {
+        let mut req = request(json!([user("Hello")]));
+        assert!(
+            fit_to_window(&mut req, &config(ContextStrategy::DropOldest))
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(req.messages.len(), 1);
+    }
+
+    #[test]
+    fn the_fit_check_holds_back_the_safety_margin() {
+        let request = request(json!([user("Hello")]));
+        let config = config(ContextStrategy::Reject);
+        assert_eq!(usable_tokens(&request.model, &config), MAX_TOKENS + BUDGET);
+
+        assert!(fits(&request, BUDGET, &config));
+        assert!(!fits(&request, BUDGET + 1, &config));
+
+        // max_tokens alone can overflow the window
+        let mut oversized = request.clone();
+        oversized.max_tokens = MAX_TOKENS + BUDGET + 1;
+        assert!(!fits(&oversized, 0, &config));
+
+        let no_margin = ContextWindowConfig {
+            safety_margin: 0.0,
+            ..config
+        };
+        assert!(fits(&request, 200_000 - MAX_TOKENS, &no_margin));
+    }
+
+    #[test]
+    fn reject_describes_the_overflow() {
+        let mut req = request(json!([user(&prose(6_000))]));
+        let estimated = estimator::estimate_request(&req);
+        assert!(estimated > BUDGET);
+
+        let Err(AnthropicError::ContextWindowExceeded {
+            input_tokens,
+            max_tokens,
+            usable_tokens,
+            context_window,
+            ..
+        }) = fit_to_window(&mut req, &config(ContextStrategy::Reject))
+        else {
+            panic!("expected the request to be rejected");
+        };
+        assert_eq!(input_tokens, estimated);
+        assert_eq!(max_tokens, MAX_TOKENS);
+        assert_eq!(usable_tokens, 190_000);
+        assert_eq!(context_window, 200_000);
+    }
+
+    #[test]
+    fn drop_oldest_cuts_before_whole_turns() {
+        let mut req = request(json!([
+            user(&prose(3_000)),
+            assistant("First answer"),
+            user(&prose(1_500)),
+            assistant("Second answer"),
+            user("Latest question")
+        ]));
+
+        // Dropping the first turn is enough; the second is kept
+        let report = fit_to_window(&mut req, &config(ContextStrategy::DropOldest))
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.dropped_messages, 2);
+        assert!(report.final_input_tokens <= BUDGET);
+        assert_eq!(req.messages.len(), 3);
+        assert_eq!(first_text(&req.messages[1]), "Second answer");
+    }
+
+    #[test]
+    fn drop_oldest_keeps_tool_pairs_together() {
+        let mut req = request(json!([
+            user("Read the file"),
+            tool_use("toolu_1"),
+            tool_result("toolu_1", &prose(6_000)),
+            assistant("It is long"),
+            user("Summarize it")
+        ]));
+
+        // The tool result does not start a turn, so the cut comes after the pair
+        let report = fit_to_window(&mut req, &config(ContextStrategy::DropOldest))
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.dropped_messages, 4);
+        assert_eq!(req.messages.len(), 1);
+        assert_eq!(first_text(&req.messages[0]), "Summarize it");
+    }
+
+    #[test]
+    fn drop_oldest_never_drops_the_last_turn() {
+        let mut req = request(json!([user("Hello"), assistant("Hi"), user(&prose(6_000))]));
+        assert!(matches!(
+            fit_to_window(&mut req, &config(ContextStrategy::DropOldest)),
+            Err(AnthropicError::ContextWindowExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn truncate_tool_results_shortens_the_largest_first() {
+        let small = prose(500);
+        let mut req = request(json!([
+            user("Read both files"),
+            tool_use("toolu_1"),
+            tool_result("toolu_1", &small),
+            tool_use("toolu_2"),
+            tool_result("toolu_2", &prose(6_000)),
+            user("Compare them")
+        ]));
+
+        let report = fit_to_window(&mut req, &config(ContextStrategy::TruncateToolResults))
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.truncated_tool_results, 1);
+        assert!(report.final_input_tokens <= BUDGET);
+
+        let text = |m: usize| match &req.messages[m].content[0] {
+            AnthropicMessageContent::ToolResult { content, .. } => match &content[0] {
+                AnthropicToolResultContent::Tool(ToolContent::Text { text }) => text.clone(),
+                _ => panic!("expected text"),
+            },
+            _ => panic!("expected a tool result"),
+        };
+        assert_eq!(text(2), small);
+        assert!(text(4).ends_with("to fit the context window ...]"));
+    }
+}
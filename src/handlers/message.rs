@@ -1,7 +1,23 @@
+use crate::agent::runner::{self, AgentRun};
 use crate::api::AnthropicClient;
+use crate::bindings::theater::simple::message_server_host;
 use crate::bindings::theater::simple::runtime::log;
+use crate::bindings::theater::simple::timing;
+use crate::context::compaction::{self, CachedSummary, CompactionPlan, CompactionReport};
+use crate::context::continuation::{self, ContinuationConfig, ContinuationReport};
+use crate::context::{normalize, prefill, window};
+use crate::schema::{structured, tool_input};
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicCompletionResponse, AnthropicCost, AnthropicError,
+    AnthropicMessage, AnthropicMessageContent, AnthropicModelInfo, AnthropicRequest, AnthropicResponse,
+    AnthropicStopReason, AnthropicUsage, CompletionEstimate, ProxyStats, TokenCountSource,
+};
+use crate::tokens::estimator;
+use crate::types::budget::ANONYMOUS_CALLER;
+use crate::types::rate_limit::TokenEstimate;
+use crate::types::usage::UsageRecord;
 use crate::types::state::State;
-use genai_types::{ProxyRequest, ProxyResponse};
+use std::collections::HashMap;
 
 pub fn handle_request(
     data: Vec<u8>,
@@ -10,7 +26,7 @@ pub fn handle_request(
     log("Handling request in anthropic-proxy actor");
 
     // Parse the state
-    let state: State = match serde_json::from_slice(&state_bytes) {
+    let mut state: State = match serde_json::from_slice(&state_bytes) {
         Ok(s) => s,
         Err(e) => {
             log(&format!("Error parsing state: {}", e));
@@ -24,14 +40,15 @@ pub fn handle_request(
         String::from_utf8_lossy(&data)
     ));
 
-    // Parse the request using the shared AnthropicRequest type
-    let request: ProxyRequest = match serde_json::from_slice(&data) {
+    // Parse the request. AnthropicRequest accepts everything the shared ProxyRequest
+    // type sends, plus the proxy-specific operations.
+    let request: AnthropicRequest = match serde_json::from_slice(&data) {
         Ok(req) => req,
         Err(e) => {
             log(&format!("Error parsing request: {}", e));
 
             // Try to respond with a properly formatted error
-            let error_response = ProxyResponse::Error {
+            let error_response = AnthropicResponse::Error {
                 error: format!("Invalid request format: {}", e),
             };
 
@@ -43,44 +60,239 @@ pub fn handle_request(
     };
 
     // Create Anthropic client
-    let client = AnthropicClient::new(state.api_key.clone());
+    let client = AnthropicClient::new(
+        state.api_key.clone(),
+        state.config.circuit_breaker.clone(),
+        state.config.prompt_caching.clone(),
+    );
+
+    for session_id in state.sessions.expire(timing::now(), &state.config.sessions) {
+        log(&format!("Session expired: {}", session_id));
+    }
 
     // Process based on operation type
     let response = match request {
-        ProxyRequest::GenerateCompletion { request } => {
+        AnthropicRequest::GenerateCompletion {
+            request,
+            caller,
+            continuation,
+        } => {
             log(&format!(
                 "Generating completion with model: {}",
                 request.model
             ));
 
-            match client.generate_completion(request.into(), &state.config.retry_config) {
-                Ok(completion) => ProxyResponse::Completion {
-                    completion: completion.into(),
-                },
+            let continuation = continuation.unwrap_or_else(|| state.config.continuation.clone());
+            match generate_completion(
+                &client,
+                &mut state,
+                request,
+                caller.as_deref(),
+                &continuation,
+            ) {
+                Ok((mut completion, prefill)) => {
+                    if state.config.prefill.prepend_to_response {
+                        if let Some(prefill) = &prefill {
+                            prefill::prepend(&mut completion, prefill);
+                        }
+                    }
+                    AnthropicResponse::Completion { completion }
+                }
                 Err(e) => {
                     log(&format!("Error generating completion: {}", e));
-                    ProxyResponse::Error {
+                    AnthropicResponse::Error {
                         error: format!("Failed to generate completion: {}", e),
                     }
                 }
             }
         }
 
-        ProxyRequest::ListModels => {
+        AnthropicRequest::ListModels => {
             log("Listing available models");
 
-            match client.list_models() {
-                Ok(models) => ProxyResponse::ListModels {
-                    models: models.into_iter().map(|m| m.into()).collect(),
-                },
+            match client.list_models(&mut state.circuit) {
+                Ok(models) => AnthropicResponse::ListModels { models },
                 Err(e) => {
                     log(&format!("Error listing models: {}", e));
-                    ProxyResponse::Error {
+                    AnthropicResponse::Error {
                         error: format!("Failed to list models: {}", e),
                     }
                 }
             }
         }
+
+        AnthropicRequest::GetStats => {
+            log("Reporting proxy stats");
+
+            AnthropicResponse::Stats {
+                stats: ProxyStats {
+                    circuit: state.circuit.clone(),
+                    rate_limit_buckets: state.rate_limiter.buckets.clone(),
+                    upstream_rate_limits: state.upstream_limits.clone(),
+                    spend: state.budget_ledger.callers.clone(),
+                    prompt_cache: state.prompt_cache.clone(),
+                },
+            }
+        }
+
+        AnthropicRequest::Estimate { request } => {
+            log(&format!("Estimating request for model: {}", request.model));
+
+            AnthropicResponse::Estimate {
+                estimate: estimate_completion(&client, &mut state, &request),
+            }
+        }
+
+        AnthropicRequest::StructuredCompletion {
+            request,
+            schema,
+            caller,
+            max_retries,
+        } => {
+            log(&format!(
+                "Generating structured completion with model: {}",
+                request.model
+            ));
+
+            let max_retries = max_retries.unwrap_or(structured::DEFAULT_MAX_RETRIES);
+            match structured_completion(
+                &client,
+                &mut state,
+                request,
+                &schema,
+                caller.as_deref(),
+                max_retries,
+            ) {
+                Ok((value, completion)) => {
+                    AnthropicResponse::StructuredOutput { value, completion }
+                }
+                Err(e) => {
+                    log(&format!("Error generating structured completion: {}", e));
+                    AnthropicResponse::Error {
+                        error: format!("Failed to generate structured completion: {}", e),
+                    }
+                }
+            }
+        }
+
+        AnthropicRequest::CreateSession { request, caller } => {
+            log(&format!("Creating session with model: {}", request.model));
+
+            match state.sessions.create(
+                request,
+                caller,
+                None,
+                timing::now(),
+                &state.config.sessions,
+            ) {
+                Ok(session) => AnthropicResponse::Session { session },
+                Err(e) => AnthropicResponse::Error {
+                    error: format!("Failed to create session: {}", e),
+                },
+            }
+        }
+
+        AnthropicRequest::AppendAndComplete {
+            session_id,
+            message,
+        } => {
+            log(&format!("Continuing session: {}", session_id));
+
+            match append_and_complete(&client, &mut state, &session_id, message) {
+                Ok(completion) => AnthropicResponse::Completion { completion },
+                Err(e) => {
+                    log(&format!("Error continuing session: {}", e));
+                    AnthropicResponse::Error {
+                        error: format!("Failed to continue session: {}", e),
+                    }
+                }
+            }
+        }
+
+        AnthropicRequest::GetSession { session_id } => match state.sessions.get(&session_id) {
+            Ok(session) => AnthropicResponse::Session {
+                session: session.clone(),
+            },
+            Err(e) => AnthropicResponse::Error {
+                error: format!("Failed to get session: {}", e),
+            },
+        },
+
+        AnthropicRequest::ForkSession { session_id } => {
+            log(&format!("Forking session: {}", session_id));
+
+            match state
+                .sessions
+                .fork(&session_id, timing::now(), &state.config.sessions)
+            {
+                Ok(session) => AnthropicResponse::Session { session },
+                Err(e) => AnthropicResponse::Error {
+                    error: format!("Failed to fork session: {}", e),
+                },
+            }
+        }
+
+        AnthropicRequest::DeleteSession { session_id } => {
+            log(&format!("Deleting session: {}", session_id));
+
+            match state.sessions.delete(&session_id) {
+                Ok(()) => AnthropicResponse::SessionDeleted { session_id },
+                Err(e) => AnthropicResponse::Error {
+                    error: format!("Failed to delete session: {}", e),
+                },
+            }
+        }
+
+        AnthropicRequest::RunAgent {
+            request,
+            tool_actors,
+            mcp_actors,
+            caller,
+            max_iterations,
+        } => {
+            log(&format!("Running agent with model: {}", request.model));
+
+            let max_iterations = max_iterations.unwrap_or(state.config.agent.max_iterations);
+            match run_agent(
+                &client,
+                &mut state,
+                request,
+                &tool_actors,
+                &mcp_actors,
+                caller.as_deref(),
+                max_iterations,
+            ) {
+                Ok(run) => AnthropicResponse::AgentRun { run },
+                Err(e) => {
+                    log(&format!("Error running agent: {}", e));
+                    AnthropicResponse::Error {
+                        error: format!("Failed to run agent: {}", e),
+                    }
+                }
+            }
+        }
+
+        AnthropicRequest::QueryUsage { query } => {
+            log("Querying usage ledger");
+
+            AnthropicResponse::UsageReport {
+                report: state.usage_ledger.query(&query),
+            }
+        }
+
+        AnthropicRequest::ExportUsage { query, format } => {
+            log(&format!("Exporting usage ledger as {:?}", format));
+
+            match state.usage_ledger.export(&query, format) {
+                Ok(data) => AnthropicResponse::UsageExport { format, data },
+                Err(e) => {
+                    log(&format!("Error exporting usage ledger: {}", e));
+                    AnthropicResponse::Error {
+                        error: format!("Failed to export usage: {}", e),
+                    }
+                }
+            }
+        }
     };
 
     // Serialize the response
@@ -92,6 +304,582 @@ pub fn handle_request(
         }
     };
 
+    // Serialize the updated state
+    let updated_state = match serde_json::to_vec(&state) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log(&format!("Error serializing state: {}", e));
+            return Err(format!("Failed to serialize state: {}", e));
+        }
+    };
+
     // Return the updated state and response
-    Ok((Some(state_bytes), (Some(response_bytes),)))
+    Ok((Some(updated_state), (Some(response_bytes),)))
+}
+
+/// Generate a completion and validate the tool calls in it.
+///
+/// With `tool_validation.max_retries` set, invalid tool inputs are sent back to the
+/// model as error tool results and the request is repeated. Every attempt is recorded
+/// in the budget and usage ledgers; the returned usage is that of the last attempt.
+///
+/// Also returns the assistant prefill the returned completion continues, if any. A
+/// retry answers the feedback message instead, so it continues no prefill.
+fn generate_completion(
+    client: &AnthropicClient,
+    state: &mut State,
+    mut request: AnthropicCompletionRequest,
+    caller: Option<&str>,
+    continuation: &ContinuationConfig,
+) -> Result<(AnthropicCompletionResponse, Option<String>), AnthropicError> {
+    let config = state.config.tool_validation.clone();
+    let mut retries = 0;
+
+    // The prefill is read from the request as sent, after same-role messages are merged
+    prepare_messages(state, &mut request)?;
+    let mut prefill = prefill::text(&request);
+
+    loop {
+        let mut completion = complete_turn(client, state, &request, caller, continuation)?;
+        if !config.enabled {
+            return Ok((completion, prefill));
+        }
+
+        let errors = tool_input::validate_tool_uses(&request, &completion);
+        if errors.is_empty() || retries >= config.max_retries {
+            if !errors.is_empty() {
+                log(&format!("{} tool calls have invalid input", errors.len()));
+            }
+            completion.tool_input_errors = errors;
+            return Ok((completion, prefill));
+        }
+
+        retries += 1;
+        prefill = None;
+        log(&format!(
+            "{} tool calls have invalid input, asking again (retry {} of {})",
+            errors.len(),
+            retries,
+            config.max_retries
+        ));
+        request.messages.push(AnthropicMessage {
+            role: completion.role.clone(),
+            content: completion.content.clone(),
+        });
+        request
+            .messages
+            .push(tool_input::feedback_message(&completion, &errors));
+    }
+}
+
+/// Generate one assistant turn, continuing it while the API pauses it or cuts it off.
+///
+/// Each round is recorded in the budget and usage ledgers. The returned completion
+/// holds the content of every round and their summed usage and cost.
+fn complete_turn(
+    client: &AnthropicClient,
+    state: &mut State,
+    request: &AnthropicCompletionRequest,
+    caller: Option<&str>,
+    config: &ContinuationConfig,
+) -> Result<AnthropicCompletionResponse, AnthropicError> {
+    let mut completion = send_completion(client, state, request.clone(), caller)?;
+    let mut report = ContinuationReport::default();
+
+    loop {
+        // Output tokens the next round may use, if capped below the request's max_tokens
+        let output_limit = match completion.stop_reason {
+            AnthropicStopReason::PauseTurn
+                if config.pause_turn && report.pause_turns < config.max_pause_turns =>
+            {
+                report.pause_turns += 1;
+                log(&format!(
+                    "Turn paused, continuing ({} of {})",
+                    report.pause_turns, config.max_pause_turns
+                ));
+                None
+            }
+            AnthropicStopReason::MaxTokens
+                if config.max_tokens
+                    && report.max_tokens_rounds < config.max_tokens_rounds
+                    && continuation::can_continue_text(&completion) =>
+            {
+                let remaining = config
+                    .max_total_output_tokens
+                    .map(|cap| cap.saturating_sub(completion.usage.output_tokens));
+                if remaining == Some(0) {
+                    log("Output cut off at max_tokens and the total output cap is reached");
+                    break;
+                }
+                report.max_tokens_rounds += 1;
+                log(&format!(
+                    "Output cut off at max_tokens, continuing ({} of {})",
+                    report.max_tokens_rounds, config.max_tokens_rounds
+                ));
+                remaining
+            }
+            _ => break,
+        };
+
+        let mut next = continuation::continue_request(request, &completion);
+        if let Some(limit) = output_limit {
+            next.max_tokens = next.max_tokens.min(limit);
+        }
+        let round = send_completion(client, state, next, caller)?;
+        continuation::merge(&mut completion, round);
+    }
+
+    if report.pause_turns > 0 || report.max_tokens_rounds > 0 {
+        completion.continuation = Some(report);
+    }
+
+    Ok(completion)
+}
+
+/// Generate a value matching `schema` by forcing a call to the synthetic output tool.
+///
+/// Outputs that do not match are sent back to the model as an error tool result, up
+/// to `max_retries` times.
+fn structured_completion(
+    client: &AnthropicClient,
+    state: &mut State,
+    mut request: AnthropicCompletionRequest,
+    schema: &serde_json::Value,
+    caller: Option<&str>,
+    max_retries: u32,
+) -> Result<(serde_json::Value, AnthropicCompletionResponse), AnthropicError> {
+    structured::prepare(&mut request, schema);
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+        let completion = send_completion(client, state, request.clone(), caller)?;
+
+        let mut errors = tool_input::validate_tool_uses(&request, &completion);
+        if errors.is_empty() {
+            return structured::extract(&completion, schema)
+                .map(|value| (value, completion))
+                .ok_or_else(|| {
+                    AnthropicError::InvalidResponse(
+                        "Model did not call the structured output tool".to_string(),
+                    )
+                });
+        }
+
+        if attempts > max_retries {
+            return Err(AnthropicError::StructuredOutputInvalid {
+                attempts,
+                violations: errors.swap_remove(0).violations,
+            });
+        }
+
+        log(&format!(
+            "Structured output does not match the schema, retrying ({} of {})",
+            attempts, max_retries
+        ));
+        request.messages.push(AnthropicMessage {
+            role: completion.role.clone(),
+            content: completion.content.clone(),
+        });
+        request
+            .messages
+            .push(tool_input::feedback_message(&completion, &errors));
+    }
+}
+
+/// Normalize a request's messages and trim its assistant prefill.
+///
+/// The API rejects a prefill ending in whitespace, so it is trimmed even when
+/// normalization is turned off.
+fn prepare_messages(
+    state: &State,
+    request: &mut AnthropicCompletionRequest,
+) -> Result<(), AnthropicError> {
+    for repair in normalize::normalize(request, &state.config.normalization)? {
+        log(&format!("Repaired messages: {}", repair));
+    }
+    if prefill::trim(request) {
+        log("Trimmed trailing whitespace from the assistant prefill");
+    }
+    Ok(())
+}
+
+/// Send one completion request, applying the caller's budget and the rate limits for the model
+fn send_completion(
+    client: &AnthropicClient,
+    state: &mut State,
+    mut request: AnthropicCompletionRequest,
+    caller: Option<&str>,
+) -> Result<AnthropicCompletionResponse, AnthropicError> {
+    prepare_messages(state, &mut request)?;
+
+    apply_budget(state, &mut request, caller.unwrap_or(ANONYMOUS_CALLER))?;
+
+    let compaction = compact(client, state, &mut request, caller);
+
+    let context_management = window::fit_to_window(&mut request, &state.config.context_window)?;
+    if let Some(report) = &context_management {
+        log(&format!(
+            "Fitted request into context window: {:?}, ~{} -> ~{} tokens",
+            report.strategy, report.original_input_tokens, report.final_input_tokens
+        ));
+    }
+
+    let mut result = dispatch(client, state, request, caller);
+    if let Ok(completion) = &mut result {
+        completion.context_management = context_management;
+        completion.compaction = compaction;
+    }
+
+    result
+}
+
+/// Send a request within the rate limits for its model, and record the completion
+/// in the caller's budget and the usage ledger.
+///
+/// Every call to the API goes through here, including summary calls.
+fn dispatch(
+    client: &AnthropicClient,
+    state: &mut State,
+    request: AnthropicCompletionRequest,
+    caller: Option<&str>,
+) -> Result<AnthropicCompletionResponse, AnthropicError> {
+    let model = request.model.clone();
+    let rate_limits = &state.config.rate_limits;
+    let limits = rate_limits.models.get(&model).cloned();
+    let estimate = TokenEstimate::for_request(&request);
+    let now = timing::now();
+
+    let mut wait_ms = match &limits {
+        Some(limits) => state.rate_limiter.wait_time(&model, limits, &estimate, now),
+        None => 0,
+    };
+
+    // Slow down pre-emptively as the upstream budget nears zero
+    if rate_limits.adaptive {
+        if let Some(upstream) = state.upstream_limits.get(&model) {
+            let delay = upstream.pacing_delay(&estimate, rate_limits.slowdown_threshold, now);
+            wait_ms = wait_ms.max(delay);
+        }
+    }
+
+    if wait_ms > rate_limits.max_wait_ms {
+        log(&format!(
+            "Rate limit for {} would require waiting {} ms, rejecting",
+            model, wait_ms
+        ));
+        return Err(AnthropicError::RateLimitExceeded {
+            retry_after: Some(wait_ms.div_ceil(1000)),
+        });
+    }
+
+    if wait_ms > 0 {
+        log(&format!("Rate limit for {} reached, waiting {} ms", model, wait_ms));
+        let _ = timing::sleep(wait_ms);
+    }
+
+    if let Some(limits) = &limits {
+        state
+            .rate_limiter
+            .acquire(&model, limits, &estimate, timing::now());
+    }
+
+    let upstream = state.upstream_limits.entry(model.clone()).or_default();
+    let started = timing::now();
+    let mut result = client.generate_completion(
+        request,
+        &state.config.retry_config,
+        &mut state.circuit,
+        upstream,
+    );
+    let finished = timing::now();
+
+    if limits.is_some() {
+        let usage = result.as_ref().ok().map(|completion| &completion.usage);
+        state.rate_limiter.reconcile(&model, &estimate, usage);
+    }
+
+    if let Ok(completion) = &mut result {
+        record_completion(state, completion, caller, finished - started, finished);
+    }
+
+    result
+}
+
+/// Generate the next turn of a session.
+///
+/// The message and the reply are only added to the history once the completion succeeds,
+/// so a failed turn can simply be retried. An assistant message is a prefill; the history
+/// keeps it and the reply as sent and received.
+fn append_and_complete(
+    client: &AnthropicClient,
+    state: &mut State,
+    session_id: &str,
+    message: AnthropicMessage,
+) -> Result<AnthropicCompletionResponse, AnthropicError> {
+    let session = state.sessions.get(session_id)?;
+    let caller = session.caller.clone();
+    let mut request = session.request.clone();
+    request.messages.push(message.clone());
+
+    let continuation = state.config.continuation.clone();
+    let (mut completion, prefill) =
+        generate_completion(client, state, request, caller.as_deref(), &continuation)?;
+
+    let session = state.sessions.get_mut(session_id)?;
+    session.request.messages.push(message);
+    session.request.messages.push(AnthropicMessage {
+        role: completion.role.clone(),
+        content: completion.content.clone(),
+    });
+    session.updated_at = timing::now();
+
+    if state.config.prefill.prepend_to_response {
+        if let Some(prefill) = &prefill {
+            prefill::prepend(&mut completion, prefill);
+        }
+    }
+
+    Ok(completion)
+}
+
+/// Run the tool loop until the model ends its turn or `max_iterations` completions
+/// have been generated.
+///
+/// The tools of `mcp_actors` are listed first and added to the request. Tool calls are
+/// sent to their actors with a message server request. Every completion is recorded in
+/// the budget and usage ledgers like any other.
+fn run_agent(
+    client: &AnthropicClient,
+    state: &mut State,
+    mut request: AnthropicCompletionRequest,
+    tool_actors: &HashMap<String, String>,
+    mcp_actors: &[String],
+    caller: Option<&str>,
+    max_iterations: u32,
+) -> Result<AgentRun, AnthropicError> {
+    let mut send = |actor_id: &str, message: &[u8]| message_server_host::request(actor_id, message);
+    let mut routes = runner::actor_routes(tool_actors);
+    runner::add_mcp_tools(&mut request, &mut routes, mcp_actors, &mut send)?;
+
+    let continuation = state.config.continuation.clone();
+    let mut usage = AnthropicUsage::default();
+    let mut cost = AnthropicCost::default();
+    let mut iterations = 0;
+
+    loop {
+        iterations += 1;
+        let (completion, _) =
+            generate_completion(client, state, request.clone(), caller, &continuation)?;
+        usage.add(&completion.usage);
+        if let Some(completion_cost) = &completion.cost {
+            cost.add(completion_cost);
+        }
+        request.messages.push(AnthropicMessage {
+            role: completion.role.clone(),
+            content: completion.content.clone(),
+        });
+
+        let wants_tools = matches!(completion.stop_reason, AnthropicStopReason::ToolUse);
+        if !wants_tools || iterations >= max_iterations {
+            if wants_tools {
+                log(&format!(
+                    "Agent stopped after {} iterations with tool calls pending",
+                    iterations
+                ));
+            }
+            return Ok(AgentRun {
+                messages: request.messages,
+                completion,
+                iterations,
+                iteration_limit_reached: wants_tools,
+                usage,
+                cost,
+            });
+        }
+
+        log(&format!("Running tool calls (iteration {})", iterations));
+        let results = runner::run_tool_calls(&completion, &routes, &mut send);
+        request.messages.push(results);
+    }
+}
+
+/// Price a completion and record it in the caller's budget and the usage ledger
+fn record_completion(
+    state: &mut State,
+    completion: &mut AnthropicCompletionResponse,
+    caller: Option<&str>,
+    latency_ms: u64,
+    finished: u64,
+) {
+    let cost = AnthropicModelInfo::get_pricing(&completion.model).cost(&completion.usage);
+    completion.cost = Some(cost.clone());
+
+    state
+        .prompt_cache
+        .entry(completion.model.clone())
+        .or_default()
+        .record(&completion.usage);
+
+    state.budget_ledger.record(
+        caller.unwrap_or(ANONYMOUS_CALLER),
+        &completion.usage,
+        cost.total_usd,
+        finished,
+    );
+
+    if state.config.usage_ledger.enabled {
+        let record = UsageRecord::new(completion, caller, cost.total_usd, latency_ms, finished);
+        let dropped = state
+            .usage_ledger
+            .append(record, state.config.usage_ledger.max_records);
+        if dropped > 0 {
+            log(&format!(
+                "WARNING: usage ledger is full; dropped {} oldest record(s)",
+                dropped
+            ));
+        }
+    }
+}
+
+/// Summarize the older turns of a long conversation, reusing cached summaries.
+///
+/// A failed summary is logged and the request is sent uncompacted, leaving the
+/// context window strategy to deal with it.
+fn compact(
+    client: &AnthropicClient,
+    state: &mut State,
+    request: &mut AnthropicCompletionRequest,
+    caller: Option<&str>,
+) -> Option<CompactionReport> {
+    let config = state.config.compaction.clone();
+    let plan = compaction::plan(request, &config, &state.summaries)?;
+    let original_input_tokens = estimator::estimate_request(request);
+
+    let (cut, summary, cached) = match plan {
+        CompactionPlan::Reuse { cut, summary } => (cut, summary, true),
+        CompactionPlan::Summarize {
+            cut,
+            key,
+            request: mut summary_request,
+        } => {
+            log(&format!(
+                "Summarizing {} messages with {}",
+                cut, config.summary_model
+            ));
+
+            // The summary is a completion like any other: budgeted, rate limited and recorded
+            let budget_caller = caller.unwrap_or(ANONYMOUS_CALLER);
+            let result = apply_budget(state, &mut summary_request, budget_caller)
+                .and_then(|()| dispatch(client, state, summary_request, caller));
+
+            let completion = match result {
+                Ok(completion) => completion,
+                Err(e) => {
+                    log(&format!("Error summarizing conversation, sending it whole: {}", e));
+                    return None;
+                }
+            };
+            let finished = timing::now();
+
+            let summary: String = completion
+                .content
+                .iter()
+                .filter_map(|content| match content {
+                    AnthropicMessageContent::Text { text, .. } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            state.summaries.insert(
+                key,
+                CachedSummary {
+                    summary: summary.clone(),
+                    messages: cut,
+                    created_at: finished,
+                },
+                state.config.max_cache_size,
+            );
+            (cut, summary, false)
+        }
+    };
+
+    compaction::apply(request, cut, &summary);
+    let report = CompactionReport {
+        summary_model: config.summary_model,
+        summarized_messages: cut,
+        cached,
+        original_input_tokens,
+        final_input_tokens: estimator::estimate_request(request),
+    };
+    log(&format!(
+        "Compacted conversation: {} messages summarized{}, ~{} -> ~{} tokens",
+        report.summarized_messages,
+        if cached { " (cached)" } else { "" },
+        report.original_input_tokens,
+        report.final_input_tokens
+    ));
+
+    Some(report)
+}
+
+/// Project the input tokens, worst-case cost and context fit of a request
+fn estimate_completion(
+    client: &AnthropicClient,
+    state: &mut State,
+    request: &AnthropicCompletionRequest,
+) -> CompletionEstimate {
+    let counted = client.count_tokens(request, &mut state.circuit);
+    let (input_tokens, input_tokens_source) = match counted {
+        Ok(tokens) => (tokens, TokenCountSource::CountTokens),
+        Err(e) => {
+            log(&format!("Token counting failed, using heuristic: {}", e));
+            (
+                TokenEstimate::for_request(request).input_tokens,
+                TokenCountSource::Heuristic,
+            )
+        }
+    };
+
+    let context_window = AnthropicModelInfo::get_max_tokens(&request.model);
+    let cost = AnthropicModelInfo::get_pricing(&request.model).cost(&AnthropicUsage {
+        input_tokens,
+        output_tokens: request.max_tokens,
+        ..Default::default()
+    });
+
+    CompletionEstimate {
+        model: request.model.clone(),
+        input_tokens,
+        input_tokens_source,
+        max_output_tokens: request.max_tokens,
+        context_window,
+        fits_context_window: window::fits(request, input_tokens, &state.config.context_window),
+        input_cost_usd: cost.input_usd,
+        max_output_cost_usd: cost.output_usd,
+        max_total_cost_usd: cost.total_usd,
+    }
+}
+
+/// Enforce the caller's budget, rejecting or downgrading the request once it is used up
+fn apply_budget(
+    state: &mut State,
+    request: &mut AnthropicCompletionRequest,
+    caller: &str,
+) -> Result<(), AnthropicError> {
+    match state
+        .budget_ledger
+        .enforce(&state.config.budgets, caller, request, timing::now())
+    {
+        Ok(Some(note)) => {
+            log(&note);
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => {
+            log(&format!("Rejecting request from {}: {}", caller, e));
+            Err(e)
+        }
+    }
 }
@@ -1,6 +1,10 @@
+mod agent;
 mod api;
 mod bindings;
+mod context;
 mod handlers;
+mod schema;
+mod tokens;
 mod types;
 
 use crate::bindings::exports::theater::simple::actor::Guest;
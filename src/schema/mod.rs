@@ -0,0 +1,3 @@
+pub mod structured;
+pub mod tool_input;
+pub mod validator;
@@ -0,0 +1,210 @@
+//! Structured output: forcing the model to answer through a synthetic tool whose
+//! input schema is the requested output schema.
+
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicCompletionResponse, AnthropicMessageContent,
+    AnthropicToolChoice,
+};
+use crate::types::tools::AnthropicTool;
+use mcp_protocol::tool::Tool;
+use serde_json::{json, Value};
+
+/// Name of the synthetic tool the model is forced to call
+pub const STRUCTURED_OUTPUT_TOOL: &str = "structured_output";
+
+/// Property that holds the output when the schema is not an object schema
+const WRAPPED_PROPERTY: &str = "value";
+
+/// Keywords holding definitions that `$ref`s point into, lifted out of a wrapped schema
+const DEFINITION_KEYWORDS: [&str; 2] = ["$defs", "definitions"];
+
+/// Default number of times a response that does not match the schema is retried
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
+
+/// Restrict a request to the synthetic output tool and force the model to call it.
+///
+/// Tool inputs must be objects, so any other schema is wrapped in an object with a
+/// single `value` property. Its definitions move to the wrapper's root and its other
+/// local `$ref`s are rewritten, so they still resolve.
+pub fn prepare(request: &mut AnthropicCompletionRequest, schema: &Value) {
+    request.tools = Some(vec![AnthropicTool::Custom(Tool {
+        name: STRUCTURED_OUTPUT_TOOL.to_string(),
+        description: Some(
+            "Respond by calling this tool. Its input is your complete answer.".to_string(),
+        ),
+        input_schema: tool_schema(schema),
+        annotations: None,
+    })]);
+    request.tool_choice = Some(AnthropicToolChoice::specific(STRUCTURED_OUTPUT_TOOL));
+}
+
+/// Pull the output out of the synthetic tool call, if the model made one
+pub fn extract(completion: &AnthropicCompletionResponse, schema: &Value) -> Option<Value> {
+    let input = completion
+        .content
+        .iter()
+        .find_map(|content| match content {
+            AnthropicMessageContent::ToolUse { name, input, .. }
+                if name == STRUCTURED_OUTPUT_TOOL =>
+            {
+                Some(input)
+            }
+            _ => None,
+        })?;
+
+    if is_object_schema(schema) {
+        Some(input.clone())
+    } else {
+        input.get(WRAPPED_PROPERTY).cloned()
+    }
+}
+
+/// The input schema for the synthetic tool
+fn tool_schema(schema: &Value) -> Value {
+    if is_object_schema(schema) {
+        return schema.clone();
+    }
+
+    let mut inner = schema.clone();
+    let mut wrapper = json!({
+        "type": "object",
+        "required": [WRAPPED_PROPERTY],
+        "additionalProperties": false,
+    });
+    for key in DEFINITION_KEYWORDS {
+        if let Some(definitions) = inner.as_object_mut().and_then(|o| o.remove(key)) {
+            wrapper[key] = definitions;
+        }
+    }
+    wrapper["properties"] = json!({ WRAPPED_PROPERTY: inner });
+    rewrite_refs(&mut wrapper);
+    wrapper
+}
+
+/// Point local `$ref`s at the wrapped schema, except those into the lifted definitions
+fn rewrite_refs(schema: &mut Value) {
+    match schema {
+        Value::Object(map) => {
+            if let Some(Value::String(reference)) = map.get_mut("$ref") {
+                let into_definitions = DEFINITION_KEYWORDS
+                    .iter()
+                    .any(|key| reference.starts_with(&format!("#/{}/", key)));
+                if reference.starts_with('#') && !into_definitions {
+                    let pointer = format!("#/properties/{}{}", WRAPPED_PROPERTY, &reference[1..]);
+                    *reference = pointer;
+                }
+            }
+            map.values_mut().for_each(rewrite_refs);
+        }
+        Value::Array(items) => items.iter_mut().for_each(rewrite_refs),
+        _ => {}
+    }
+}
+
+fn is_object_schema(schema: &Value) -> bool {
+    schema.get("type").and_then(Value::as_str) == Some("object")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::validator;
+
+    fn request() -> AnthropicCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": "List the steps" }] }
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn prepared_schema(schema: &Value) -> Value {
+        let mut req = request();
+        prepare(&mut req, schema);
+        let tools = req.tools.unwrap();
+        let [AnthropicTool::Custom(tool)] = tools.as_slice() else {
+            panic!("expected only the structured output tool");
+        };
+        assert_eq!(tool.name, STRUCTURED_OUTPUT_TOOL);
+        tool.input_schema.clone()
+    }
+
+    fn completion(input: Value) -> AnthropicCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5-20250929",
+            "content": [
+                { "type": "tool_use", "id": "toolu_1", "name": STRUCTURED_OUTPUT_TOOL, "input": input }
+            ],
+            "stop_reason": "tool_use",
+            "stop_sequence": null,
+            "usage": { "input_tokens": 50, "output_tokens": 20 }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn object_schemas_are_used_as_is() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "name": { "type": "string" } },
+            "required": ["name"]
+        });
+        assert_eq!(prepared_schema(&schema), schema);
+
+        let output = completion(json!({ "name": "Ada" }));
+        assert_eq!(extract(&output, &schema), Some(json!({ "name": "Ada" })));
+    }
+
+    #[test]
+    fn other_schemas_are_wrapped_with_their_refs_intact() {
+        let schema = json!({
+            "type": "array",
+            "items": { "$ref": "#/$defs/step" },
+            "$defs": {
+                "step": {
+                    "type": "object",
+                    "properties": {
+                        "title": { "type": "string" },
+                        "substeps": { "$ref": "#" }
+                    },
+                    "required": ["title"]
+                }
+            }
+        });
+
+        let wrapped = prepared_schema(&schema);
+        assert!(wrapped["properties"]["value"].get("$defs").is_none());
+        assert_eq!(
+            wrapped["$defs"]["step"]["properties"]["substeps"]["$ref"],
+            "#/properties/value"
+        );
+        assert_eq!(
+            wrapped["properties"]["value"]["items"]["$ref"],
+            "#/$defs/step"
+        );
+
+        let valid = json!({ "value": [{ "title": "Plan", "substeps": [{ "title": "Draft" }] }] });
+        assert!(validator::validate(&wrapped, &valid).is_empty());
+        let invalid = json!({ "value": [{ "title": "Plan", "substeps": [{}] }] });
+        assert_eq!(validator::validate(&wrapped, &invalid).len(), 1);
+
+        let output = completion(valid);
+        assert_eq!(
+            extract(&output, &schema),
+            Some(json!([{ "title": "Plan", "substeps": [{ "title": "Draft" }] }]))
+        );
+    }
+
+    #[test]
+    fn nothing_is_extracted_without_the_tool_call() {
+        let mut output = completion(json!({}));
+        output.content = vec![AnthropicMessageContent::text("I would rather not")];
+        assert_eq!(extract(&output, &json!({ "type": "string" })), None);
+    }
+}
@@ -0,0 +1,253 @@
+//! Checking the tool calls in a response against the tools' input schemas.
+
+use crate::schema::validator::{self, SchemaViolation};
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicCompletionResponse, AnthropicMessage,
+    AnthropicMessageContent,
+};
+use crate::types::tools::AnthropicTool;
+use mcp_protocol::tool::ToolContent;
+use serde::{Deserialize, Serialize};
+
+/// Tool input validation configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ToolValidationConfig {
+    /// Whether tool inputs are validated
+    pub enabled: bool,
+
+    /// How many times to send the violations back to the model and ask again
+    pub max_retries: u32,
+}
+
+impl Default for ToolValidationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_retries: 0,
+        }
+    }
+}
+
+/// A tool call whose input does not match the tool's schema
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolInputError {
+    /// ID of the tool_use block
+    pub tool_use_id: String,
+
+    /// Name of the tool that was called
+    pub tool_name: String,
+
+    /// Where the input deviates from the schema
+    pub violations: Vec<SchemaViolation>,
+}
+
+/// Validate every tool_use in a completion against the request's tool definitions.
+///
+/// A call to a tool the request did not define is reported as a violation at the root.
+/// Only custom tools have an input schema; calls to other tools are not checked.
+pub fn validate_tool_uses(
+    request: &AnthropicCompletionRequest,
+    completion: &AnthropicCompletionResponse,
+) -> Vec<ToolInputError> {
+    let tools = request.tools.as_deref().unwrap_or_default();
+
+    completion
+        .content
+        .iter()
+        .filter_map(|content| {
+            let AnthropicMessageContent::ToolUse { id, name, input } = content else {
+                return None;
+            };
+
+            let violations = match tools.iter().find(|tool| tool.name() == name) {
+                Some(AnthropicTool::Custom(tool)) => validator::validate(&tool.input_schema, input),
+                Some(_) => Vec::new(),
+                None => vec![SchemaViolation {
+                    path: String::new(),
+                    message: format!("no tool named '{}' was provided", name),
+                }],
+            };
+
+            (!violations.is_empty()).then(|| ToolInputError {
+                tool_use_id: id.clone(),
+                tool_name: name.clone(),
+                violations,
+            })
+        })
+        .collect()
+}
+
+/// Build the turn that reports invalid tool inputs back to the model.
+///
+/// Every tool_use needs a tool_result, so valid calls from the same response are
+/// answered too, asking the model to repeat them with the corrected ones.
+pub fn feedback_message(
+    completion: &AnthropicCompletionResponse,
+    errors: &[ToolInputError],
+) -> AnthropicMessage {
+    let content = completion
+        .content
+        .iter()
+        .filter_map(|content| {
+            let AnthropicMessageContent::ToolUse { id, .. } = content else {
+                return None;
+            };
+
+            let text = match errors.iter().find(|error| &error.tool_use_id == id) {
+                Some(error) => format!(
+                    "{}\nCall the tool again with corrected input.",
+                    error_text(error)
+                ),
+                None => "Not run because another tool call in this turn had invalid input. Repeat this call along with the corrected ones.".to_string(),
+            };
+
+            Some(AnthropicMessageContent::ToolResult {
+                tool_use_id: id.clone(),
+                content: vec![ToolContent::Text { text }.into()],
+                is_error: Some(true),
+            })
+        })
+        .collect();
+
+    AnthropicMessage {
+        role: "user".to_string(),
+        content,
+    }
+}
+
+/// Describe where a tool call's input deviates from the tool's input_schema
+pub fn error_text(error: &ToolInputError) -> String {
+    let violations: Vec<String> = error
+        .violations
+        .iter()
+        .map(|v| {
+            let path = if v.path.is_empty() { "input" } else { &v.path };
+            format!("- {}: {}", path, v.message)
+        })
+        .collect();
+    format!(
+        "The input for {} does not match its input_schema:\n{}",
+        error.tool_name,
+        violations.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::api::AnthropicToolResultContent;
+    use serde_json::json;
+
+    fn request() -> AnthropicCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [{ "role": "user", "content": [{ "type": "text", "text": "Tidy up" }] }],
+            "tools": [
+                {
+                    "name": "read_file",
+                    "input_schema": {
+                        "type": "object",
+                        "properties": { "path": { "type": "string" } },
+                        "required": ["path"]
+                    }
+                },
+                { "type": "web_search_20250305", "name": "web_search" },
+                { "type": "bash_20250124", "name": "bash" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn completion() -> AnthropicCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5-20250929",
+            "content": [
+                { "type": "text", "text": "Reading the files." },
+                { "type": "tool_use", "id": "toolu_1", "name": "read_file", "input": { "path": "a.txt" } },
+                { "type": "tool_use", "id": "toolu_2", "name": "read_file", "input": { "path": 7 } },
+                { "type": "tool_use", "id": "toolu_3", "name": "web_search", "input": { "anything": true } },
+                { "type": "tool_use", "id": "toolu_4", "name": "bash", "input": 42 },
+                { "type": "tool_use", "id": "toolu_5", "name": "delete_file", "input": {} }
+            ],
+            "stop_reason": "tool_use",
+            "stop_sequence": null,
+            "usage": { "input_tokens": 100, "output_tokens": 40 }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn invalid_and_unknown_calls_are_reported() {
+        let errors = validate_tool_uses(&request(), &completion());
+
+        // The valid call and the server and client tool calls are not reported
+        let ids: Vec<&str> = errors.iter().map(|e| e.tool_use_id.as_str()).collect();
+        assert_eq!(ids, ["toolu_2", "toolu_5"]);
+
+        assert_eq!(errors[0].tool_name, "read_file");
+        assert_eq!(
+            errors[0].violations,
+            [SchemaViolation {
+                path: "/path".to_string(),
+                message: "expected string, got number".to_string(),
+            }]
+        );
+
+        assert_eq!(errors[1].tool_name, "delete_file");
+        assert_eq!(
+            errors[1].violations,
+            [SchemaViolation {
+                path: String::new(),
+                message: "no tool named 'delete_file' was provided".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn feedback_answers_every_call() {
+        let completion = completion();
+        let errors = validate_tool_uses(&request(), &completion);
+        let message = feedback_message(&completion, &errors);
+
+        assert_eq!(message.role, "user");
+        let results: Vec<(&str, &str)> = message
+            .content
+            .iter()
+            .map(|content| {
+                let AnthropicMessageContent::ToolResult {
+                    tool_use_id,
+                    content,
+                    is_error,
+                } = content
+                else {
+                    panic!("expected a tool result");
+                };
+                assert_eq!(*is_error, Some(true));
+                let [AnthropicToolResultContent::Tool(ToolContent::Text { text })] =
+                    content.as_slice()
+                else {
+                    panic!("expected one text item");
+                };
+                (tool_use_id.as_str(), text.as_str())
+            })
+            .collect();
+
+        let ids: Vec<&str> = results.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, ["toolu_1", "toolu_2", "toolu_3", "toolu_4", "toolu_5"]);
+
+        assert!(results[0].1.starts_with("Not run because another tool call"));
+        assert_eq!(
+            results[1].1,
+            "The input for read_file does not match its input_schema:\n- /path: expected string, got number\nCall the tool again with corrected input."
+        );
+        assert_eq!(
+            results[4].1,
+            "The input for delete_file does not match its input_schema:\n- input: no tool named 'delete_file' was provided\nCall the tool again with corrected input."
+        );
+    }
+}
@@ -0,0 +1,478 @@
+//! A JSON Schema validator covering the keywords used in tool input schemas.
+//!
+//! Supported: `type`, `enum`, `const`, `properties`, `required`,
+//! `additionalProperties`, `items`, `minItems`, `maxItems`, `uniqueItems`,
+//! `minLength`, `maxLength`, `minimum`, `maximum`, `exclusiveMinimum`,
+//! `exclusiveMaximum`, `multipleOf`, `allOf`, `anyOf`, `oneOf`, `not`, and local
+//! `$ref`s into `$defs` or `definitions`. Other keywords, such as `pattern` and
+//! `format`, are ignored.
+//!
+//! A `$ref` that leads back to itself without descending into the value, or `$ref`s
+//! nested more than 64 deep, are reported as violations instead of being followed.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+
+/// Most `$ref`s followed inside one another before validation gives up
+const MAX_REF_DEPTH: usize = 64;
+
+/// A single place where a value does not match its schema
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SchemaViolation {
+    /// JSON pointer to the offending value, empty for the root
+    pub path: String,
+
+    /// What is wrong with it
+    pub message: String,
+}
+
+/// Validate a value against a schema, returning every violation found
+pub fn validate(schema: &Value, value: &Value) -> Vec<SchemaViolation> {
+    let cx = Context {
+        root: schema,
+        refs: RefCell::new(Vec::new()),
+    };
+    let mut violations = Vec::new();
+    check(&cx, schema, value, "", &mut violations);
+    violations
+}
+
+/// The schema being validated against and the `$ref`s being followed
+struct Context<'a> {
+    root: &'a Value,
+
+    /// Each `$ref` currently being followed, with the path of the value it applies to.
+    /// Meeting the same pair again means the references loop without consuming any of
+    /// the value.
+    refs: RefCell<Vec<(String, String)>>,
+}
+
+fn check(cx: &Context, schema: &Value, value: &Value, path: &str, out: &mut Vec<SchemaViolation>) {
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            violate(out, path, "no value is allowed here".to_string());
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let key = (reference.to_string(), path.to_string());
+        let (circular, depth) = {
+            let refs = cx.refs.borrow();
+            (refs.contains(&key), refs.len())
+        };
+        if circular {
+            violate(out, path, format!("circular $ref '{}'", reference));
+        } else if depth >= MAX_REF_DEPTH {
+            violate(
+                out,
+                path,
+                format!("$refs nested more than {} deep", MAX_REF_DEPTH),
+            );
+        } else {
+            match resolve(cx.root, reference) {
+                Some(target) => {
+                    cx.refs.borrow_mut().push(key);
+                    check(cx, target, value, path, out);
+                    cx.refs.borrow_mut().pop();
+                }
+                None => violate(out, path, format!("unresolvable $ref '{}'", reference)),
+            }
+        }
+    }
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+            violate(
+                out,
+                path,
+                format!("expected {}, got {}", types.join(" or "), type_name(value)),
+            );
+            // Keyword checks below assume the right type, so stop here
+            return;
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            violate(
+                out,
+                path,
+                format!("{} is not one of {}", value, Value::Array(allowed.clone())),
+            );
+        }
+    }
+
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            violate(out, path, format!("expected {}, got {}", constant, value));
+        }
+    }
+
+    match value {
+        Value::Object(object) => check_object(cx, schema, object, path, out),
+        Value::Array(items) => check_array(cx, schema, items, path, out),
+        Value::String(text) => check_string(schema, text, path, out),
+        Value::Number(_) => check_number(schema, value.as_f64().unwrap_or(0.0), path, out),
+        _ => {}
+    }
+
+    check_combinators(cx, schema, value, path, out);
+}
+
+fn check_object(
+    cx: &Context,
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+    out: &mut Vec<SchemaViolation>,
+) {
+    if let Some(required) = schema.get("required").and_then(Value::as_array) {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                violate(out, path, format!("missing required property '{}'", name));
+            }
+        }
+    }
+
+    let properties = schema.get("properties").and_then(Value::as_object);
+    for (name, item) in object {
+        let item_path = format!("{}/{}", path, escape(name));
+        match properties.and_then(|p| p.get(name)) {
+            Some(property) => check(cx, property, item, &item_path, out),
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => {
+                    violate(out, path, format!("unexpected property '{}'", name))
+                }
+                Some(additional) => check(cx, additional, item, &item_path, out),
+                None => {}
+            },
+        }
+    }
+}
+
+fn check_array(
+    cx: &Context,
+    schema: &Map<String, Value>,
+    items: &[Value],
+    path: &str,
+    out: &mut Vec<SchemaViolation>,
+) {
+    if let Some(item_schema) = schema.get("items") {
+        for (index, item) in items.iter().enumerate() {
+            check(cx, item_schema, item, &format!("{}/{}", path, index), out);
+        }
+    }
+
+    if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+        if (items.len() as u64) < min {
+            violate(
+                out,
+                path,
+                format!("expected at least {} items, got {}", min, items.len()),
+            );
+        }
+    }
+    if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+        if items.len() as u64 > max {
+            violate(
+                out,
+                path,
+                format!("expected at most {} items, got {}", max, items.len()),
+            );
+        }
+    }
+    if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+        let duplicate = items
+            .iter()
+            .enumerate()
+            .any(|(i, item)| items[..i].contains(item));
+        if duplicate {
+            violate(out, path, "items must be unique".to_string());
+        }
+    }
+}
+
+fn check_string(
+    schema: &Map<String, Value>,
+    text: &str,
+    path: &str,
+    out: &mut Vec<SchemaViolation>,
+) {
+    let length = text.chars().count() as u64;
+    if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+        if length < min {
+            violate(
+                out,
+                path,
+                format!("expected at least {} characters, got {}", min, length),
+            );
+        }
+    }
+    if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+        if length > max {
+            violate(
+                out,
+                path,
+                format!("expected at most {} characters, got {}", max, length),
+            );
+        }
+    }
+}
+
+fn check_number(
+    schema: &Map<String, Value>,
+    number: f64,
+    path: &str,
+    out: &mut Vec<SchemaViolation>,
+) {
+    let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+
+    if let Some(min) = bound("minimum").filter(|min| number < *min) {
+        violate(
+            out,
+            path,
+            format!("{} is less than the minimum {}", number, min),
+        );
+    }
+    if let Some(max) = bound("maximum").filter(|max| number > *max) {
+        violate(
+            out,
+            path,
+            format!("{} is greater than the maximum {}", number, max),
+        );
+    }
+    if let Some(min) = bound("exclusiveMinimum").filter(|min| number <= *min) {
+        violate(
+            out,
+            path,
+            format!("{} must be greater than {}", number, min),
+        );
+    }
+    if let Some(max) = bound("exclusiveMaximum").filter(|max| number >= *max) {
+        violate(out, path, format!("{} must be less than {}", number, max));
+    }
+    if let Some(step) = bound("multipleOf").filter(|step| *step > 0.0) {
+        let quotient = number / step;
+        if (quotient - quotient.round()).abs() > 1e-9 {
+            violate(
+                out,
+                path,
+                format!("{} is not a multiple of {}", number, step),
+            );
+        }
+    }
+}
+
+fn check_combinators(
+    cx: &Context,
+    schema: &Map<String, Value>,
+    value: &Value,
+    path: &str,
+    out: &mut Vec<SchemaViolation>,
+) {
+    let matches = |sub: &Value| {
+        let mut scratch = Vec::new();
+        check(cx, sub, value, path, &mut scratch);
+        scratch.is_empty()
+    };
+
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+        for sub in all {
+            check(cx, sub, value, path, out);
+        }
+    }
+    if let Some(any) = schema.get("anyOf").and_then(Value::as_array) {
+        if !any.iter().any(matches) {
+            violate(out, path, "does not match any schema in anyOf".to_string());
+        }
+    }
+    if let Some(one) = schema.get("oneOf").and_then(Value::as_array) {
+        let count = one.iter().filter(|sub| matches(sub)).count();
+        if count != 1 {
+            violate(
+                out,
+                path,
+                format!("matches {} schemas in oneOf, expected exactly 1", count),
+            );
+        }
+    }
+    if let Some(not) = schema.get("not") {
+        if matches(not) {
+            violate(out, path, "matches a schema it must not match".to_string());
+        }
+    }
+}
+
+/// Resolve a local `$ref` such as `#/$defs/address`
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    root.pointer(pointer)
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value
+            .as_f64()
+            .is_some_and(|n| value.is_i64() || value.is_u64() || n.fract() == 0.0),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Escape a property name for use in a JSON pointer
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+fn violate(out: &mut Vec<SchemaViolation>, path: &str, message: String) {
+    out.push(SchemaViolation {
+        path: path.to_string(),
+        message,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string", "minLength": 1 },
+                "mode": { "enum": ["read", "write"] },
+                "lines": { "type": "array", "items": { "$ref": "#/$defs/line" }, "maxItems": 2 }
+            },
+            "required": ["path"],
+            "additionalProperties": false,
+            "$defs": { "line": { "type": "integer", "minimum": 1 } }
+        })
+    }
+
+    #[test]
+    fn valid_input_has_no_violations() {
+        let input = json!({ "path": "src/lib.rs", "mode": "read", "lines": [1, 20] });
+        assert!(validate(&schema(), &input).is_empty());
+    }
+
+    #[test]
+    fn reports_every_violation_with_its_path() {
+        let input = json!({ "mode": "append", "lines": [0, 2.5, 3], "extra": true });
+        let mut paths: Vec<String> = validate(&schema(), &input)
+            .into_iter()
+            .map(|v| v.path)
+            .collect();
+        paths.sort();
+
+        // required and additionalProperties at the root, then maxItems, minimum,
+        // integer and enum
+        assert_eq!(
+            paths,
+            vec!["", "", "/lines", "/lines/0", "/lines/1", "/mode"]
+        );
+    }
+
+    #[test]
+    fn wrong_root_type_stops_early() {
+        let violations = validate(&schema(), &json!("src/lib.rs"));
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].message, "expected object, got string");
+    }
+
+    #[test]
+    fn combinators() {
+        let schema = json!({ "oneOf": [{ "type": "string" }, { "type": "integer" }] });
+        assert!(validate(&schema, &json!(3)).is_empty());
+        assert_eq!(validate(&schema, &json!(true)).len(), 1);
+
+        // An integer is also a number, so it matches both branches
+        let schema = json!({ "anyOf": [{ "type": "number" }, { "type": "integer" }] });
+        assert!(validate(&schema, &json!(3)).is_empty());
+        let schema = json!({ "oneOf": [{ "type": "number" }, { "type": "integer" }] });
+        assert_eq!(validate(&schema, &json!(3)).len(), 1);
+    }
+
+    #[test]
+    fn circular_refs_are_reported() {
+        let schema = json!({ "$ref": "#" });
+        assert_eq!(
+            validate(&schema, &json!({})),
+            vec![SchemaViolation {
+                path: String::new(),
+                message: "circular $ref '#'".to_string(),
+            }]
+        );
+
+        let schema = json!({
+            "$ref": "#/$defs/a",
+            "$defs": { "a": { "$ref": "#/$defs/b" }, "b": { "allOf": [{ "$ref": "#/$defs/a" }] } }
+        });
+        let violations = validate(&schema, &json!(1));
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].message, "circular $ref '#/$defs/a'");
+    }
+
+    #[test]
+    fn recursive_schemas_follow_the_value() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "name": { "type": "string" },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } }
+                    }
+                }
+            }
+        });
+        let tree = json!({ "name": "a", "children": [{ "name": "b", "children": [{ "name": 3 }] }] });
+        let violations = validate(&schema, &tree);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].path, "/children/0/children/0/name");
+    }
+
+    #[test]
+    fn ref_depth_is_capped() {
+        // A chain of distinct $refs, each pointing at the next
+        let mut defs = Map::new();
+        for i in 0..=MAX_REF_DEPTH {
+            defs.insert(i.to_string(), json!({ "$ref": format!("#/$defs/{}", i + 1) }));
+        }
+        let schema = json!({ "$ref": "#/$defs/0", "$defs": defs });
+
+        let violations = validate(&schema, &json!(null));
+        assert_eq!(violations.len(), 1);
+        assert_eq!(
+            violations[0].message,
+            format!("$refs nested more than {} deep", MAX_REF_DEPTH)
+        );
+    }
+}
@@ -0,0 +1,587 @@
+//! Offline token estimation.
+//!
+//! A deterministic approximation of Claude's tokenizer for use when the token
+//! counting endpoint is unavailable or too slow to call, such as for rate limiting,
+//! budget checks and context trimming. Text is split into byte-pair-like pieces
+//! (words, digit runs, punctuation runs, whitespace) and each piece is costed.
+//! Images are costed by pixel dimensions and PDFs by page count, following
+//! Anthropic's published formulas.
+
+use crate::types::api::{
+    AnthropicCompletionRequest, AnthropicMessageContent, AnthropicToolResultBlock,
+    AnthropicToolResultContent, SearchResult,
+};
+use crate::types::tools::AnthropicTool;
+use mcp_protocol::tool::ToolContent;
+use serde_json::Value;
+
+/// Calibration multiplier for natural-language text
+const TEXT_CALIBRATION: f64 = 1.0;
+
+/// Calibration multiplier for JSON (tool inputs and schemas), which tokenizes less efficiently
+const JSON_CALIBRATION: f64 = 1.1;
+
+/// Tokens added per message for role and turn markers
+const MESSAGE_OVERHEAD: u32 = 4;
+
+/// Tokens added per content block
+const BLOCK_OVERHEAD: u32 = 3;
+
+/// Tokens for the tool use system prompt Anthropic adds when tools are present
+const TOOL_USE_SYSTEM_PROMPT: u32 = 346;
+
+/// Images whose long edge exceeds this are downscaled before tokenization
+const IMAGE_MAX_EDGE: f64 = 1568.0;
+
+/// Images above this many pixels (about 1,600 tokens) are downscaled before tokenization
+const IMAGE_MAX_PIXELS: f64 = 1_200_000.0;
+
+/// Pixels per image token
+const IMAGE_PIXELS_PER_TOKEN: f64 = 750.0;
+
+/// Tokens assumed for an image whose dimensions cannot be read (the maximum for one image)
+const IMAGE_FALLBACK_TOKENS: u32 = 1600;
+
+/// Extracted text tokens assumed per PDF page (Anthropic quotes 1,500-3,000)
+const PDF_TEXT_TOKENS_PER_PAGE: u32 = 2250;
+
+/// Pixel size each PDF page is rendered at (US letter at 144 dpi)
+const PDF_PAGE_PIXELS: (u32, u32) = (1224, 1584);
+
+/// Estimate the input tokens of a completion request
+pub fn estimate_request(request: &AnthropicCompletionRequest) -> u32 {
+    let mut tokens = 0;
+
+    if let Some(system) = &request.system {
+        tokens += estimate_text(system) + BLOCK_OVERHEAD;
+    }
+
+    if let Some(tools) = request.tools.as_ref().filter(|tools| !tools.is_empty()) {
+        tokens += TOOL_USE_SYSTEM_PROMPT;
+        for tool in tools {
+            tokens += serde_json::to_value(tool)
+                .map(|value| estimate_json(&value))
+                .unwrap_or(0);
+            if let AnthropicTool::Client(tool) = tool {
+                tokens += tool.system_prompt_tokens();
+            }
+        }
+    }
+
+    for message in &request.messages {
+        tokens += MESSAGE_OVERHEAD;
+        for content in &message.content {
+            tokens += estimate_content(content);
+        }
+    }
+
+    tokens
+}
+
+/// Estimate the tokens of a single content block
+pub fn estimate_content(content: &AnthropicMessageContent) -> u32 {
+    BLOCK_OVERHEAD
+        + match content {
+            AnthropicMessageContent::Text { text, .. } => estimate_text(text),
+            AnthropicMessageContent::ToolUse { id, name, input } => {
+                estimate_text(id) + estimate_text(name) + estimate_json(input)
+            }
+            AnthropicMessageContent::ToolResult {
+                tool_use_id,
+                content,
+                ..
+            } => {
+                estimate_text(tool_use_id) + content.iter().map(estimate_tool_content).sum::<u32>()
+            }
+            AnthropicMessageContent::SearchResult(result) => estimate_search_result(result),
+            AnthropicMessageContent::ServerToolUse { id, name, input } => {
+                estimate_text(id) + estimate_text(name) + estimate_json(input)
+            }
+            AnthropicMessageContent::WebSearchToolResult {
+                tool_use_id,
+                content,
+            }
+            | AnthropicMessageContent::WebFetchToolResult {
+                tool_use_id,
+                content,
+            }
+            | AnthropicMessageContent::CodeExecutionToolResult {
+                tool_use_id,
+                content,
+            }
+            | AnthropicMessageContent::BashCodeExecutionToolResult {
+                tool_use_id,
+                content,
+            }
+            | AnthropicMessageContent::TextEditorCodeExecutionToolResult {
+                tool_use_id,
+                content,
+            }
+            | AnthropicMessageContent::McpToolResult {
+                tool_use_id,
+                content,
+                ..
+            } => estimate_text(tool_use_id) + estimate_json(content),
+            AnthropicMessageContent::McpToolUse {
+                id,
+                name,
+                server_name,
+                input,
+            } => {
+                estimate_text(id)
+                    + estimate_text(name)
+                    + estimate_text(server_name)
+                    + estimate_json(input)
+            }
+        }
+}
+
+/// Estimate the tokens of a tool result content item
+pub fn estimate_tool_content(content: &AnthropicToolResultContent) -> u32 {
+    match content {
+        AnthropicToolResultContent::Tool(ToolContent::Text { text }) => estimate_text(text),
+        AnthropicToolResultContent::Tool(ToolContent::Image { data, .. }) => {
+            estimate_image_data(data)
+        }
+        // Audio is not understood by the model, so it is sent through as encoded data
+        AnthropicToolResultContent::Tool(ToolContent::Audio { data, .. }) => estimate_text(data),
+        AnthropicToolResultContent::Tool(ToolContent::Resource { resource }) => {
+            estimate_resource(resource)
+        }
+        AnthropicToolResultContent::Block(AnthropicToolResultBlock::SearchResult(result)) => {
+            estimate_search_result(result)
+        }
+    }
+}
+
+/// Estimate the tokens of a search result, including its source and title
+fn estimate_search_result(result: &SearchResult) -> u32 {
+    estimate_text(&result.source)
+        + estimate_text(&result.title)
+        + result
+            .content
+            .iter()
+            .map(|passage| BLOCK_OVERHEAD + estimate_text(&passage.text))
+            .sum::<u32>()
+}
+
+/// Estimate the tokens of an MCP embedded resource
+fn estimate_resource(resource: &Value) -> u32 {
+    let mime_type = resource
+        .get("mimeType")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+
+    if let Some(text) = resource.get("text").and_then(|v| v.as_str()) {
+        return estimate_text(text);
+    }
+
+    match resource.get("blob").and_then(|v| v.as_str()) {
+        Some(blob) if mime_type == "application/pdf" => match decode_base64(blob) {
+            Some(bytes) => estimate_pdf_pages(pdf_page_count(&bytes)),
+            None => estimate_pdf_pages(1),
+        },
+        Some(blob) if mime_type.starts_with("image/") => estimate_image_data(blob),
+        _ => estimate_json(resource),
+    }
+}
+
+/// Estimate the tokens of natural-language text
+pub fn estimate_text(text: &str) -> u32 {
+    (text_pieces(text) * TEXT_CALIBRATION).ceil() as u32
+}
+
+/// Estimate the tokens of a JSON value in its compact serialized form
+pub fn estimate_json(value: &Value) -> u32 {
+    let serialized = value.to_string();
+    (text_pieces(&serialized) * JSON_CALIBRATION).ceil() as u32
+}
+
+/// Estimate the tokens of an image with the given pixel dimensions
+pub fn estimate_image(width: u32, height: u32) -> u32 {
+    if width == 0 || height == 0 {
+        return 0;
+    }
+
+    let (width, height) = (width as f64, height as f64);
+    let scale = 1.0f64
+        .min(IMAGE_MAX_EDGE / width.max(height))
+        .min((IMAGE_MAX_PIXELS / (width * height)).sqrt());
+
+    let pixels = (width * scale).floor() * (height * scale).floor();
+    (pixels / IMAGE_PIXELS_PER_TOKEN).ceil() as u32
+}
+
+/// Estimate the tokens of a PDF with the given number of pages
+pub fn estimate_pdf_pages(pages: u32) -> u32 {
+    let (width, height) = PDF_PAGE_PIXELS;
+    pages * (PDF_TEXT_TOKENS_PER_PAGE + estimate_image(width, height))
+}
+
+/// Estimate the tokens of a base64-encoded image
+fn estimate_image_data(data: &str) -> u32 {
+    decode_base64(data)
+        .and_then(|bytes| image_dimensions(&bytes))
+        .map(|(width, height)| estimate_image(width, height))
+        .unwrap_or(IMAGE_FALLBACK_TOKENS)
+}
+
+/// Cost text as byte-pair-like pieces.
+///
+/// Short words are a single token and longer words split roughly every four
+/// letters. A single space merges into the following piece. Digits group in
+/// threes, punctuation in pairs, and CJK characters are a token each.
+fn text_pieces(text: &str) -> f64 {
+    #[derive(PartialEq, Clone, Copy)]
+    enum Class {
+        Letter,
+        Digit,
+        Space,
+        Newline,
+        Punct,
+        Cjk,
+        Other,
+    }
+
+    fn classify(c: char) -> Class {
+        if is_cjk(c) {
+            Class::Cjk
+        } else if c.is_alphabetic() {
+            Class::Letter
+        } else if c.is_ascii_digit() {
+            Class::Digit
+        } else if c == '\n' || c == '\r' {
+            Class::Newline
+        } else if c.is_whitespace() {
+            Class::Space
+        } else if c.is_ascii() {
+            Class::Punct
+        } else {
+            Class::Other
+        }
+    }
+
+    let mut tokens = 0.0;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let class = classify(c);
+        let mut len = 1usize;
+        let mut bytes = c.len_utf8();
+
+        if class != Class::Cjk && class != Class::Other {
+            while let Some(&next) = chars.peek() {
+                if classify(next) != class {
+                    break;
+                }
+                len += 1;
+                bytes += next.len_utf8();
+                chars.next();
+            }
+        }
+
+        let len = len as f64;
+        tokens += match class {
+            Class::Letter if len <= 5.0 => 1.0,
+            Class::Letter => 1.0 + (len - 5.0) / 4.0,
+            Class::Digit => (len / 3.0).ceil(),
+            // A lone space is absorbed by the next word
+            Class::Space if len <= 1.0 => 0.0,
+            Class::Space => (len / 4.0).ceil(),
+            Class::Newline => (len / 2.0).ceil(),
+            Class::Punct => (len / 2.0).ceil(),
+            Class::Cjk => 1.0,
+            // Emoji and other symbols cost roughly one token per two UTF-8 bytes
+            Class::Other => (bytes as f64 / 2.0).ceil(),
+        };
+    }
+
+    tokens
+}
+
+/// Whether a character is CJK, which tokenizes at about one token per character
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF // Hiragana, Katakana
+        | 0x3400..=0x4DBF // CJK Extension A
+        | 0x4E00..=0x9FFF // CJK Unified Ideographs
+        | 0xAC00..=0xD7AF // Hangul Syllables
+        | 0xF900..=0xFAFF // CJK Compatibility Ideographs
+    )
+}
+
+/// Read the pixel dimensions of a PNG, GIF, JPEG or WebP image
+fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes([*bytes.get(i)?, *bytes.get(i + 1)?]) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes([*bytes.get(i)?, *bytes.get(i + 1)?]) as u32);
+    let le24 = |i: usize| {
+        Some(u32::from_le_bytes([
+            *bytes.get(i)?,
+            *bytes.get(i + 1)?,
+            *bytes.get(i + 2)?,
+            0,
+        ]))
+    };
+    let be32 = |i: usize| Some(u32::from_be_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((be32(16)?, be32(20)?));
+    }
+
+    if bytes.starts_with(b"GIF8") {
+        return Some((le16(6)?, le16(8)?));
+    }
+
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        return match bytes.get(12..16)? {
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            b"VP8 " => Some((le16(26)? & 0x3FFF, le16(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(bytes.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+            }
+            _ => None,
+        };
+    }
+
+    if bytes.starts_with(&[0xFF, 0xD8]) {
+        // Walk the JPEG segments until a start-of-frame marker
+        let mut i = 2;
+        while i + 9 < bytes.len() {
+            if bytes[i] != 0xFF {
+                return None;
+            }
+            let marker = bytes[i + 1];
+            let is_sof = matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC);
+            if is_sof {
+                return Some((be16(i + 7)?, be16(i + 5)?));
+            }
+            i += 2 + be16(i + 2)? as usize;
+        }
+    }
+
+    None
+}
+
+/// Count the pages of a PDF from its page objects, falling back to the page tree count
+fn pdf_page_count(bytes: &[u8]) -> u32 {
+    let mut page_objects = 0;
+    let mut tree_count = 0;
+
+    for (i, window) in bytes.windows(5).enumerate() {
+        if window != b"/Type" {
+            continue;
+        }
+        let rest = &bytes[i + 5..];
+        let rest = &rest[rest.iter().take_while(|b| b.is_ascii_whitespace()).count()..];
+        if rest.starts_with(b"/Page") && !rest.starts_with(b"/Pages") {
+            page_objects += 1;
+        }
+    }
+
+    // Pages inside compressed object streams are invisible, but the tree root's /Count is not
+    for (i, window) in bytes.windows(6).enumerate() {
+        if window != b"/Count" {
+            continue;
+        }
+        let digits: String = bytes[i + 6..]
+            .iter()
+            .skip_while(|b| b.is_ascii_whitespace())
+            .take_while(|b| b.is_ascii_digit())
+            .map(|&b| b as char)
+            .collect();
+        tree_count = tree_count.max(digits.parse().unwrap_or(0));
+    }
+
+    page_objects.max(tree_count).max(1)
+}
+
+/// Decode standard base64, ignoring whitespace and an optional data URL prefix
+fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    let data = match data.split_once(";base64,") {
+        Some((_, rest)) => rest,
+        None => data,
+    };
+
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for byte in data.bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            b if b.is_ascii_whitespace() => continue,
+            _ => return None,
+        };
+        buffer = ((buffer << 6) | value as u32) & 0xFFFFFF;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::api::AnthropicMessage;
+
+    /// Characters per token for a corpus sample
+    fn chars_per_token(text: &str, tokens: u32) -> f64 {
+        text.chars().count() as f64 / tokens as f64
+    }
+
+    fn encode_base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in bytes.chunks(3) {
+            let b = [
+                chunk[0],
+                *chunk.get(1).unwrap_or(&0),
+                *chunk.get(2).unwrap_or(&0),
+            ];
+            let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    fn png_header(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        bytes.extend_from_slice(&width.to_be_bytes());
+        bytes.extend_from_slice(&height.to_be_bytes());
+        bytes.extend_from_slice(&[8, 6, 0, 0, 0]);
+        bytes
+    }
+
+    // Reference ratios follow Anthropic's guidance of roughly 3.5 characters per
+    // token for English, fewer for code and JSON, and about one per CJK character.
+
+    #[test]
+    fn prose_within_expected_ratio() {
+        let text = include_str!("corpus/prose.txt");
+        let ratio = chars_per_token(text, estimate_text(text));
+        assert!((3.2..=4.6).contains(&ratio), "prose ratio {}", ratio);
+    }
+
+    #[test]
+    fn code_within_expected_ratio() {
+        let text = include_str!("corpus/code.rs.txt");
+        let ratio = chars_per_token(text, estimate_text(text));
+        assert!((2.4..=4.0).contains(&ratio), "code ratio {}", ratio);
+    }
+
+    #[test]
+    fn json_within_expected_ratio() {
+        let value: Value = serde_json::from_str(include_str!("corpus/tool_input.json")).unwrap();
+        let ratio = chars_per_token(&value.to_string(), estimate_json(&value));
+        assert!((2.0..=3.5).contains(&ratio), "json ratio {}", ratio);
+    }
+
+    #[test]
+    fn cjk_within_expected_ratio() {
+        let text = include_str!("corpus/cjk.txt").trim();
+        let ratio = chars_per_token(text, estimate_text(text));
+        assert!((0.8..=1.6).contains(&ratio), "cjk ratio {}", ratio);
+    }
+
+    #[test]
+    fn image_tokens_match_published_formula() {
+        // Values from Anthropic's vision documentation
+        assert_eq!(estimate_image(200, 200), 54);
+        assert_eq!(estimate_image(1000, 1000), 1334);
+        assert_eq!(estimate_image(1092, 1092), 1590);
+
+        // Oversized images are scaled down to the same cap
+        assert!(estimate_image(4000, 3000) <= IMAGE_FALLBACK_TOKENS);
+        assert_eq!(estimate_image(3136, 100), estimate_image(1568, 50));
+    }
+
+    #[test]
+    fn image_dimensions_read_from_headers() {
+        assert_eq!(image_dimensions(&png_header(640, 480)), Some((640, 480)));
+
+        let gif = [
+            b"GIF89a".as_slice(),
+            &320u16.to_le_bytes(),
+            &200u16.to_le_bytes(),
+        ]
+        .concat();
+        assert_eq!(image_dimensions(&gif), Some((320, 200)));
+
+        let jpeg = [
+            &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00][..],
+            &[0xFF, 0xC0, 0x00, 0x11, 0x08],
+            &768u16.to_be_bytes(),
+            &1024u16.to_be_bytes(),
+            &[0x03, 0x00, 0x00],
+        ]
+        .concat();
+        assert_eq!(image_dimensions(&jpeg), Some((1024, 768)));
+
+        let data = encode_base64(&png_header(1000, 1000));
+        assert_eq!(estimate_image_data(&data), 1334);
+        assert_eq!(
+            estimate_image_data("bm90IGFuIGltYWdl"),
+            IMAGE_FALLBACK_TOKENS
+        );
+    }
+
+    #[test]
+    fn pdf_tokens_scale_with_page_count() {
+        let pdf = b"%PDF-1.4\n1 0 obj << /Type /Pages /Kids [2 0 R 3 0 R 4 0 R] /Count 3 >>\n\
+                    2 0 obj << /Type /Page >>\n3 0 obj << /Type/Page >>\n4 0 obj << /Type /Page >>";
+        assert_eq!(pdf_page_count(pdf), 3);
+        assert_eq!(pdf_page_count(b"%PDF-1.7 << /Type /Pages /Count 12 >>"), 12);
+
+        let resource = serde_json::json!({
+            "uri": "file:///report.pdf",
+            "mimeType": "application/pdf",
+            "blob": encode_base64(pdf),
+        });
+        assert_eq!(estimate_resource(&resource), estimate_pdf_pages(3));
+        assert_eq!(estimate_pdf_pages(3), 3 * estimate_pdf_pages(1));
+    }
+
+    #[test]
+    fn request_estimate_is_deterministic_and_monotonic() {
+        let text = include_str!("corpus/prose.txt");
+        let request = |text: &str| AnthropicCompletionRequest {
+            model: "claude-3-7-sonnet-20250219".to_string(),
+            messages: vec![AnthropicMessage::new_structured(
+                "user",
+                vec![AnthropicMessageContent::text(text)],
+            )],
+            max_tokens: 1024,
+            temperature: None,
+            system: None,
+            tools: None,
+            tool_choice: None,
+            disable_parallel_tool_use: None,
+            mcp_servers: None,
+        };
+
+        let short = estimate_request(&request(&text[..200]));
+        let full = estimate_request(&request(text));
+        assert_eq!(full, estimate_request(&request(text)));
+        assert!(short < full);
+        assert_eq!(
+            full,
+            MESSAGE_OVERHEAD + BLOCK_OVERHEAD + estimate_text(text)
+        );
+    }
+}
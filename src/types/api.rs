@@ -3,15 +3,35 @@ use genai_types::{
     ToolChoice, Usage,
 };
 use genai_types::{ModelInfo, ModelPricing};
-use mcp_protocol::tool::{Tool, ToolContent};
+use crate::agent::runner::AgentRun;
+use crate::context::caching::PromptCacheStats;
+use crate::context::compaction::CompactionReport;
+use crate::context::continuation::{ContinuationConfig, ContinuationReport};
+use crate::context::window::ContextManagementReport;
+use crate::schema::tool_input::ToolInputError;
+use crate::schema::validator::SchemaViolation;
+use crate::types::budget::CallerSpend;
+use crate::types::circuit::CircuitBreaker;
+use crate::types::rate_limit::{ModelBuckets, UpstreamRateLimits};
+use crate::types::session::Session;
+use crate::types::usage::{UsageExportFormat, UsageQuery, UsageReport};
+use crate::types::tools::{AnthropicTool, CitationsConfig, McpServer};
+use mcp_protocol::tool::ToolContent;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// Different types of content that can be in a message
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum AnthropicMessageContent {
     #[serde(rename = "text")]
-    Text { text: String },
+    Text {
+        text: String,
+
+        /// Sources backing the text, passed through unchanged
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        citations: Option<Vec<serde_json::Value>>,
+    },
 
     #[serde(rename = "tool_use")]
     ToolUse {
@@ -23,16 +43,157 @@ pub enum AnthropicMessageContent {
     #[serde(rename = "tool_result")]
     ToolResult {
         tool_use_id: String,
-        content: Vec<ToolContent>,
+        content: Vec<AnthropicToolResultContent>,
         #[serde(skip_serializing_if = "Option::is_none")]
         is_error: Option<bool>,
     },
+
+    /// A retrieved snippet the model can cite
+    #[serde(rename = "search_result")]
+    SearchResult(SearchResult),
+
+    /// A call to a server tool, run by Anthropic
+    #[serde(rename = "server_tool_use")]
+    ServerToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+
+    // Server tool results keep their `content` exactly as returned. It can hold
+    // encrypted fields that must be sent back unchanged in later turns.
+    #[serde(rename = "web_search_tool_result")]
+    WebSearchToolResult {
+        tool_use_id: String,
+        content: serde_json::Value,
+    },
+
+    #[serde(rename = "web_fetch_tool_result")]
+    WebFetchToolResult {
+        tool_use_id: String,
+        content: serde_json::Value,
+    },
+
+    #[serde(rename = "code_execution_tool_result")]
+    CodeExecutionToolResult {
+        tool_use_id: String,
+        content: serde_json::Value,
+    },
+
+    #[serde(rename = "bash_code_execution_tool_result")]
+    BashCodeExecutionToolResult {
+        tool_use_id: String,
+        content: serde_json::Value,
+    },
+
+    #[serde(rename = "text_editor_code_execution_tool_result")]
+    TextEditorCodeExecutionToolResult {
+        tool_use_id: String,
+        content: serde_json::Value,
+    },
+
+    /// A call to a tool on one of the request's `mcp_servers`, made by the API
+    #[serde(rename = "mcp_tool_use")]
+    McpToolUse {
+        id: String,
+        name: String,
+        server_name: String,
+        input: serde_json::Value,
+    },
+
+    /// The MCP server's result for an `mcp_tool_use`
+    #[serde(rename = "mcp_tool_result")]
+    McpToolResult {
+        tool_use_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+        content: serde_json::Value,
+    },
+}
+
+impl AnthropicMessageContent {
+    /// Create a text block
+    pub fn text(text: impl Into<String>) -> Self {
+        AnthropicMessageContent::Text {
+            text: text.into(),
+            citations: None,
+        }
+    }
+}
+
+/// A search result, with the source and title that citations of it refer to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResult {
+    /// URL or other identifier of where the content came from
+    pub source: String,
+
+    pub title: String,
+
+    /// The text of the result, in the passages citations can point at
+    pub content: Vec<SearchResultText>,
+
+    /// Whether the result can be cited; the API default is disabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub citations: Option<CitationsConfig>,
+}
+
+impl SearchResult {
+    /// The result as plain text, for places that have no search result block
+    pub fn to_text(&self) -> String {
+        let passages: Vec<&str> = self.content.iter().map(|c| c.text.as_str()).collect();
+        format!("{} ({})\n{}", self.title, self.source, passages.join("\n"))
+    }
+}
+
+/// A passage of a search result
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename = "text")]
+pub struct SearchResultText {
+    pub text: String,
+}
+
+/// An item in a tool result's content
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum AnthropicToolResultContent {
+    /// Text, image, audio or resource content, as returned by MCP tools
+    Tool(ToolContent),
+
+    /// Content types only the Messages API defines
+    Block(AnthropicToolResultBlock),
+}
+
+/// Tool result content types that MCP's `ToolContent` cannot represent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum AnthropicToolResultBlock {
+    #[serde(rename = "search_result")]
+    SearchResult(SearchResult),
+}
+
+impl From<ToolContent> for AnthropicToolResultContent {
+    fn from(content: ToolContent) -> Self {
+        AnthropicToolResultContent::Tool(content)
+    }
+}
+
+impl From<AnthropicToolResultContent> for ToolContent {
+    fn from(content: AnthropicToolResultContent) -> Self {
+        match content {
+            AnthropicToolResultContent::Tool(content) => content,
+            AnthropicToolResultContent::Block(AnthropicToolResultBlock::SearchResult(result)) => {
+                ToolContent::Text {
+                    text: result.to_text(),
+                }
+            }
+        }
+    }
 }
 
 impl From<MessageContent> for AnthropicMessageContent {
     fn from(content: MessageContent) -> Self {
         match content {
-            MessageContent::Text { text } => AnthropicMessageContent::Text { text },
+            MessageContent::Text { text } => AnthropicMessageContent::text(text),
             MessageContent::ToolUse { id, name, input } => {
                 AnthropicMessageContent::ToolUse { id, name, input }
             }
@@ -42,7 +203,7 @@ impl From<MessageContent> for AnthropicMessageContent {
                 is_error,
             } => AnthropicMessageContent::ToolResult {
                 tool_use_id,
-                content,
+                content: content.into_iter().map(Into::into).collect(),
                 is_error,
             },
         }
@@ -104,15 +265,43 @@ pub struct AnthropicCompletionRequest {
 
     /// Tools to make available to Claude
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub tools: Option<Vec<Tool>>,
+    pub tools: Option<Vec<AnthropicTool>>,
 
     /// Tool choice configuration
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<AnthropicToolChoice>,
 
-    /// Whether to disable parallel tool use
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Whether to disable parallel tool use.
+    ///
+    /// Accepted for compatibility with `genai_types::CompletionRequest`. The Messages API
+    /// expects this inside `tool_choice`, so it is moved there before sending; see
+    /// [`AnthropicCompletionRequest::wire_tool_choice`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub disable_parallel_tool_use: Option<bool>,
+
+    /// Remote MCP servers whose tools the API calls directly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mcp_servers: Option<Vec<McpServer>>,
+}
+
+impl AnthropicCompletionRequest {
+    /// The tool choice to send, with a top-level `disable_parallel_tool_use` folded in.
+    ///
+    /// Without an explicit tool choice, the flag is sent with `auto`, which is the
+    /// API's default whenever tools are present.
+    pub fn wire_tool_choice(&self) -> Option<AnthropicToolChoice> {
+        let Some(disable) = self.disable_parallel_tool_use else {
+            return self.tool_choice.clone();
+        };
+
+        match &self.tool_choice {
+            Some(choice) => Some(choice.clone().with_default_parallel_tool_use(disable)),
+            None if self.tools.as_ref().is_some_and(|tools| !tools.is_empty()) => {
+                Some(AnthropicToolChoice::auto().with_default_parallel_tool_use(disable))
+            }
+            None => None,
+        }
+    }
 }
 
 impl From<CompletionRequest> for AnthropicCompletionRequest {
@@ -127,15 +316,18 @@ impl From<CompletionRequest> for AnthropicCompletionRequest {
             max_tokens: request.max_tokens,
             temperature: request.temperature,
             system: request.system,
-            tools: request.tools,
+            tools: request
+                .tools
+                .map(|tools| tools.into_iter().map(AnthropicTool::from).collect()),
             tool_choice: request.tool_choice.map(AnthropicToolChoice::from),
             disable_parallel_tool_use: request.disable_parallel_tool_use,
+            mcp_servers: None,
         }
     }
 }
 
 /// Information about token usage
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct AnthropicUsage {
     pub input_tokens: u32,
 
@@ -144,6 +336,68 @@ pub struct AnthropicUsage {
     pub cache_read_input_tokens: Option<u32>,
 
     pub cache_creation_input_tokens: Option<u32>,
+
+    /// Cache writes broken down by TTL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_creation: Option<AnthropicCacheCreation>,
+
+    /// Service tier the request was billed at ("standard", "priority" or "batch")
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+
+    /// Server tool requests made while generating the response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_tool_use: Option<AnthropicServerToolUsage>,
+}
+
+impl AnthropicUsage {
+    /// Add the usage of another request, as when several rounds make up one response
+    pub fn add(&mut self, other: &AnthropicUsage) {
+        fn sum(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+            match (a, b) {
+                (None, None) => None,
+                (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+            }
+        }
+
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_read_input_tokens =
+            sum(self.cache_read_input_tokens, other.cache_read_input_tokens);
+        self.cache_creation_input_tokens =
+            sum(self.cache_creation_input_tokens, other.cache_creation_input_tokens);
+        if let Some(other) = &other.cache_creation {
+            let creation = self.cache_creation.get_or_insert_with(Default::default);
+            creation.ephemeral_5m_input_tokens += other.ephemeral_5m_input_tokens;
+            creation.ephemeral_1h_input_tokens += other.ephemeral_1h_input_tokens;
+        }
+        if other.service_tier.is_some() {
+            self.service_tier = other.service_tier.clone();
+        }
+        if let Some(other) = &other.server_tool_use {
+            let tools = self.server_tool_use.get_or_insert_with(Default::default);
+            tools.web_search_requests += other.web_search_requests;
+            tools.web_fetch_requests += other.web_fetch_requests;
+        }
+    }
+}
+
+/// Server tool requests, some of which are billed on top of tokens
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AnthropicServerToolUsage {
+    pub web_search_requests: u32,
+
+    pub web_fetch_requests: u32,
+}
+
+/// Prompt cache writes broken down by TTL
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AnthropicCacheCreation {
+    pub ephemeral_5m_input_tokens: u32,
+
+    pub ephemeral_1h_input_tokens: u32,
 }
 
 impl From<Usage> for AnthropicUsage {
@@ -153,6 +407,9 @@ impl From<Usage> for AnthropicUsage {
             output_tokens: usage.output_tokens,
             cache_read_input_tokens: None,
             cache_creation_input_tokens: None,
+            cache_creation: None,
+            service_tier: None,
+            server_tool_use: None,
         }
     }
 }
@@ -182,7 +439,7 @@ pub struct AnthropicCompletionResponse {
     pub role: String,
 
     /// Reason why generation stopped
-    /// can be "end_turn", "max_tokens", "stop_sequence", "tool_use", null
+    /// can be "end_turn", "max_tokens", "stop_sequence", "tool_use", "pause_turn", null
     pub stop_reason: AnthropicStopReason,
 
     /// Stop sequence if applicable (deprecated - kept for backward compatibility)
@@ -194,6 +451,30 @@ pub struct AnthropicCompletionResponse {
 
     /// Token usage information
     pub usage: AnthropicUsage,
+
+    /// Anthropic's `request-id` response header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_request_id: Option<String>,
+
+    /// Cost of the completion, computed by the proxy from the model pricing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cost: Option<AnthropicCost>,
+
+    /// What the proxy did to fit the request into the context window, if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_management: Option<ContextManagementReport>,
+
+    /// How the proxy summarized older turns of the conversation, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compaction: Option<CompactionReport>,
+
+    /// How the proxy continued the turn over several requests, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuation: Option<ContinuationReport>,
+
+    /// Tool calls whose input does not match the tool's input_schema
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_input_errors: Vec<ToolInputError>,
 }
 
 impl From<CompletionResponse> for AnthropicCompletionResponse {
@@ -211,6 +492,12 @@ impl From<CompletionResponse> for AnthropicCompletionResponse {
             stop_sequence: response.stop_sequence,
             message_type: response.message_type,
             usage: response.usage.into(),
+            upstream_request_id: None,
+            cost: None,
+            context_management: None,
+            compaction: None,
+            continuation: None,
+            tool_input_errors: Vec::new(),
         }
     }
 }
@@ -221,20 +508,25 @@ impl From<AnthropicCompletionResponse> for CompletionResponse {
             content: response
                 .content
                 .into_iter()
-                .map(|c| match c {
-                    AnthropicMessageContent::Text { text } => MessageContent::Text { text },
+                // The shared types have no server tool blocks, so those are left out
+                .filter_map(|c| match c {
+                    AnthropicMessageContent::Text { text, .. } => {
+                        Some(MessageContent::Text { text })
+                    }
                     AnthropicMessageContent::ToolUse { id, name, input } => {
-                        MessageContent::ToolUse { id, name, input }
+                        Some(MessageContent::ToolUse { id, name, input })
                     }
                     AnthropicMessageContent::ToolResult {
                         tool_use_id,
                         content,
                         is_error,
-                    } => MessageContent::ToolResult {
+                    } => Some(MessageContent::ToolResult {
                         tool_use_id,
-                        content,
+                        // Search results become text, keeping what the tool returned
+                        content: content.into_iter().map(Into::into).collect(),
                         is_error,
-                    },
+                    }),
+                    _ => None,
                 })
                 .collect(),
             id: response.id,
@@ -266,6 +558,10 @@ pub enum AnthropicStopReason {
     /// Generation stopped because a tool was used
     #[serde(rename = "tool_use")]
     ToolUse,
+
+    /// A long server tool turn was paused and can be continued
+    #[serde(rename = "pause_turn")]
+    PauseTurn,
 }
 
 impl From<StopReason> for AnthropicStopReason {
@@ -286,6 +582,8 @@ impl From<AnthropicStopReason> for StopReason {
             AnthropicStopReason::MaxTokens => StopReason::MaxTokens,
             AnthropicStopReason::StopSequence => StopReason::StopSequence,
             AnthropicStopReason::ToolUse => StopReason::ToolUse,
+            // The shared type has no paused state; the turn did end for now
+            AnthropicStopReason::PauseTurn => StopReason::EndTurn,
         }
     }
 }
@@ -295,7 +593,93 @@ impl From<AnthropicStopReason> for StopReason {
 pub enum AnthropicRequest {
     ListModels,
 
-    GenerateCompletion { request: AnthropicCompletionRequest },
+    GenerateCompletion {
+        request: AnthropicCompletionRequest,
+
+        /// Caller key (actor ID or tenant) used for budgets
+        #[serde(default)]
+        caller: Option<String>,
+
+        /// Overrides the configured continuation settings for this request
+        #[serde(default)]
+        continuation: Option<ContinuationConfig>,
+    },
+
+    /// Report circuit breaker, rate limit and budget state
+    GetStats,
+
+    /// Query and aggregate the usage ledger
+    QueryUsage { query: UsageQuery },
+
+    /// Export the usage ledger
+    ExportUsage {
+        query: UsageQuery,
+        format: UsageExportFormat,
+    },
+
+    /// Project the token usage and cost of a request without generating a completion
+    Estimate { request: AnthropicCompletionRequest },
+
+    /// Generate a JSON value that matches `schema`
+    StructuredCompletion {
+        request: AnthropicCompletionRequest,
+        schema: serde_json::Value,
+
+        /// Caller key used for budgets
+        #[serde(default)]
+        caller: Option<String>,
+
+        /// How many times to retry an output that does not match the schema
+        #[serde(default)]
+        max_retries: Option<u32>,
+    },
+
+    /// Start a session. `request` holds the settings for every turn, and its
+    /// `messages` seed the history.
+    CreateSession {
+        request: AnthropicCompletionRequest,
+
+        /// Caller key used for budgets
+        #[serde(default)]
+        caller: Option<String>,
+    },
+
+    /// Append a message to a session's history and generate the next turn
+    AppendAndComplete {
+        session_id: String,
+        message: AnthropicMessage,
+    },
+
+    /// Fetch a session and its full history
+    GetSession { session_id: String },
+
+    /// Copy a session into a new one that continues independently
+    ForkSession { session_id: String },
+
+    /// Delete a session
+    DeleteSession { session_id: String },
+
+    /// Run the tool loop: each tool call is sent to the actor mapped to its tool, and
+    /// the results are fed back until the model ends its turn
+    RunAgent {
+        request: AnthropicCompletionRequest,
+
+        /// Actor ID that implements each tool, keyed by tool name
+        #[serde(default)]
+        tool_actors: HashMap<String, String>,
+
+        /// MCP server actors whose tools are listed and added to the request
+        #[serde(default)]
+        mcp_actors: Vec<String>,
+
+        /// Caller key used for budgets
+        #[serde(default)]
+        caller: Option<String>,
+
+        /// Overrides `agent.max_iterations` for this run
+        #[serde(default)]
+        max_iterations: Option<u32>,
+    },
 }
 
 /// Response status
@@ -321,10 +705,101 @@ pub enum AnthropicResponse {
         completion: AnthropicCompletionResponse,
     },
 
+    /// Proxy statistics
+    Stats { stats: ProxyStats },
+
+    /// Usage ledger query result
+    UsageReport { report: UsageReport },
+
+    /// Exported usage ledger
+    UsageExport {
+        format: UsageExportFormat,
+        data: String,
+    },
+
+    /// Projected usage and cost of a request
+    Estimate { estimate: CompletionEstimate },
+
+    /// Output of a structured completion, guaranteed to match the schema
+    StructuredOutput {
+        value: serde_json::Value,
+        completion: AnthropicCompletionResponse,
+    },
+
+    /// A created, fetched or forked session
+    Session { session: Session },
+
+    /// Confirmation that a session was deleted
+    SessionDeleted { session_id: String },
+
+    /// Transcript and totals of an agent run
+    AgentRun { run: AgentRun },
+
     /// Error response
     Error { error: String },
 }
 
+/// Where an input token count came from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TokenCountSource {
+    /// Counted by the Anthropic token counting endpoint
+    CountTokens,
+
+    /// Estimated offline because the endpoint was unavailable
+    Heuristic,
+}
+
+/// Projected token usage and cost of a completion request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompletionEstimate {
+    /// Model the estimate is for
+    pub model: String,
+
+    /// Projected input tokens
+    pub input_tokens: u32,
+
+    /// How the input tokens were obtained
+    pub input_tokens_source: TokenCountSource,
+
+    /// Maximum output tokens requested
+    pub max_output_tokens: u32,
+
+    /// Model context window
+    pub context_window: u32,
+
+    /// Whether the input plus `max_tokens` fits in the context window, less the
+    /// `context_window.safety_margin` that requests are held to before sending
+    pub fits_context_window: bool,
+
+    /// Cost of the input tokens in dollars
+    pub input_cost_usd: f64,
+
+    /// Worst-case cost of the output, assuming all `max_tokens` are generated
+    pub max_output_cost_usd: f64,
+
+    /// Worst-case total cost in dollars
+    pub max_total_cost_usd: f64,
+}
+
+/// Snapshot of the proxy's view of the upstream API
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyStats {
+    /// Circuit breaker state
+    pub circuit: CircuitBreaker,
+
+    /// Client-side rate limit buckets keyed by model ID
+    pub rate_limit_buckets: HashMap<String, ModelBuckets>,
+
+    /// Upstream rate limits learned from response headers, keyed by model ID
+    pub upstream_rate_limits: HashMap<String, UpstreamRateLimits>,
+
+    /// Budget spend keyed by caller key
+    pub spend: HashMap<String, CallerSpend>,
+
+    /// Prompt cache hit rates keyed by model ID
+    pub prompt_cache: HashMap<String, PromptCacheStats>,
+}
+
 /// Information about a model
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AnthropicModelInfo {
@@ -376,17 +851,193 @@ pub struct AnthropicModelPricing {
 
     /// Cost per million output tokens
     pub output_cost_per_million_tokens: f64,
+
+    /// Cost per million tokens written to the 5-minute prompt cache
+    pub cache_write_5m_cost_per_million_tokens: f64,
+
+    /// Cost per million tokens written to the 1-hour prompt cache
+    pub cache_write_1h_cost_per_million_tokens: f64,
+
+    /// Cost per million tokens read from the prompt cache
+    pub cache_read_cost_per_million_tokens: f64,
+
+    /// Higher rates that apply to requests with long prompts, if the model has them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub long_context: Option<AnthropicLongContextPricing>,
+}
+
+/// Pricing tier for prompts above a size threshold
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnthropicLongContextPricing {
+    /// Total input tokens (including cache reads and writes) above which this tier applies
+    pub threshold_input_tokens: u32,
+
+    /// Cost per million input tokens
+    pub input_cost_per_million_tokens: f64,
+
+    /// Cost per million output tokens
+    pub output_cost_per_million_tokens: f64,
 }
 
-impl From<ModelPricing> for AnthropicModelPricing {
-    fn from(pricing: ModelPricing) -> Self {
+/// Multiplier on the input price for 5-minute cache writes
+const CACHE_WRITE_5M_MULTIPLIER: f64 = 1.25;
+
+/// Multiplier on the input price for 1-hour cache writes
+const CACHE_WRITE_1H_MULTIPLIER: f64 = 2.0;
+
+/// Multiplier on the input price for cache reads
+const CACHE_READ_MULTIPLIER: f64 = 0.1;
+
+/// Multiplier applied to all prices for the batch service tier
+const BATCH_MULTIPLIER: f64 = 0.5;
+
+/// Price of one web search ($10 per 1,000), which does not depend on the model
+const WEB_SEARCH_COST_PER_REQUEST: f64 = 0.01;
+
+/// Dollar cost of a completion, broken down by token type
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AnthropicCost {
+    pub input_usd: f64,
+
+    pub output_usd: f64,
+
+    pub cache_write_usd: f64,
+
+    pub cache_read_usd: f64,
+
+    /// Web search fees, charged per search
+    #[serde(default)]
+    pub web_search_usd: f64,
+
+    pub total_usd: f64,
+
+    /// Whether the long-context tier was applied
+    pub long_context: bool,
+
+    /// Whether the batch discount was applied
+    pub batch_discount: bool,
+}
+
+impl AnthropicCost {
+    /// Add the cost of another request, such as a later round of the same turn
+    pub fn add(&mut self, other: &AnthropicCost) {
+        self.input_usd += other.input_usd;
+        self.output_usd += other.output_usd;
+        self.cache_write_usd += other.cache_write_usd;
+        self.cache_read_usd += other.cache_read_usd;
+        self.web_search_usd += other.web_search_usd;
+        self.total_usd += other.total_usd;
+        self.long_context |= other.long_context;
+        self.batch_discount |= other.batch_discount;
+    }
+}
+
+impl AnthropicModelPricing {
+    /// Create pricing from the base input and output prices, deriving the cache prices
+    pub fn new(input_cost_per_million_tokens: f64, output_cost_per_million_tokens: f64) -> Self {
         Self {
-            input_cost_per_million_tokens: pricing.input_cost_per_million_tokens,
-            output_cost_per_million_tokens: pricing.output_cost_per_million_tokens,
+            input_cost_per_million_tokens,
+            output_cost_per_million_tokens,
+            cache_write_5m_cost_per_million_tokens: input_cost_per_million_tokens
+                * CACHE_WRITE_5M_MULTIPLIER,
+            cache_write_1h_cost_per_million_tokens: input_cost_per_million_tokens
+                * CACHE_WRITE_1H_MULTIPLIER,
+            cache_read_cost_per_million_tokens: input_cost_per_million_tokens
+                * CACHE_READ_MULTIPLIER,
+            long_context: None,
+        }
+    }
+
+    /// Add a long-context tier
+    pub fn with_long_context(
+        mut self,
+        threshold_input_tokens: u32,
+        input_cost_per_million_tokens: f64,
+        output_cost_per_million_tokens: f64,
+    ) -> Self {
+        self.long_context = Some(AnthropicLongContextPricing {
+            threshold_input_tokens,
+            input_cost_per_million_tokens,
+            output_cost_per_million_tokens,
+        });
+        self
+    }
+
+    /// Dollar cost of the given token usage
+    pub fn cost(&self, usage: &AnthropicUsage) -> AnthropicCost {
+        let cache_write_tokens = usage.cache_creation_input_tokens.unwrap_or(0);
+        let cache_read_tokens = usage.cache_read_input_tokens.unwrap_or(0);
+        let total_input_tokens = usage.input_tokens + cache_write_tokens + cache_read_tokens;
+
+        // Split cache writes by TTL when the API reports the breakdown
+        let (cache_write_5m_tokens, cache_write_1h_tokens) = match &usage.cache_creation {
+            Some(breakdown) => (
+                breakdown.ephemeral_5m_input_tokens,
+                breakdown.ephemeral_1h_input_tokens,
+            ),
+            None => (cache_write_tokens, 0),
+        };
+
+        // Long prompts switch to the long-context rates. Cache prices scale with the input rate.
+        let long_context = self
+            .long_context
+            .as_ref()
+            .filter(|tier| total_input_tokens > tier.threshold_input_tokens);
+        let (input_rate, output_rate, cache_scale) = match long_context {
+            Some(tier) => (
+                tier.input_cost_per_million_tokens,
+                tier.output_cost_per_million_tokens,
+                tier.input_cost_per_million_tokens / self.input_cost_per_million_tokens,
+            ),
+            None => (
+                self.input_cost_per_million_tokens,
+                self.output_cost_per_million_tokens,
+                1.0,
+            ),
+        };
+
+        let batch_discount = usage.service_tier.as_deref() == Some("batch");
+        let scale = if batch_discount { BATCH_MULTIPLIER } else { 1.0 } / 1_000_000.0;
+
+        let input_usd = usage.input_tokens as f64 * input_rate * scale;
+        let output_usd = usage.output_tokens as f64 * output_rate * scale;
+        let cache_write_usd = (cache_write_5m_tokens as f64
+            * self.cache_write_5m_cost_per_million_tokens
+            + cache_write_1h_tokens as f64 * self.cache_write_1h_cost_per_million_tokens)
+            * cache_scale
+            * scale;
+        let cache_read_usd =
+            cache_read_tokens as f64 * self.cache_read_cost_per_million_tokens * cache_scale * scale;
+
+        let web_search_usd = usage
+            .server_tool_use
+            .as_ref()
+            .map_or(0.0, |server| {
+                server.web_search_requests as f64 * WEB_SEARCH_COST_PER_REQUEST
+            });
+
+        AnthropicCost {
+            input_usd,
+            output_usd,
+            cache_write_usd,
+            cache_read_usd,
+            web_search_usd,
+            total_usd: input_usd + output_usd + cache_write_usd + cache_read_usd + web_search_usd,
+            long_context: long_context.is_some(),
+            batch_discount,
         }
     }
 }
 
+impl From<ModelPricing> for AnthropicModelPricing {
+    fn from(pricing: ModelPricing) -> Self {
+        Self::new(
+            pricing.input_cost_per_million_tokens,
+            pricing.output_cost_per_million_tokens,
+        )
+    }
+}
+
 impl From<AnthropicModelPricing> for ModelPricing {
     fn from(pricing: AnthropicModelPricing) -> Self {
         Self {
@@ -397,9 +1048,20 @@ impl From<AnthropicModelPricing> for ModelPricing {
 }
 
 impl AnthropicModelInfo {
+    /// Get the shortest prompt prefix, in tokens, that a given model will cache
+    pub fn get_min_cacheable_tokens(model_id: &str) -> u32 {
+        match model_id {
+            "claude-3-5-haiku-20241022" | "claude-3-haiku-20240307" => 2048,
+            _ => 1024,
+        }
+    }
+
     /// Get maximum tokens for a given model ID
     pub fn get_max_tokens(model_id: &str) -> u32 {
         match model_id {
+            // Claude 4 models
+            "claude-sonnet-4-20250514" | "claude-sonnet-4-5-20250929" => 200000,
+
             // Claude 3.7 models
             "claude-3-7-sonnet-20250219" => 200000,
 
@@ -424,63 +1086,64 @@ impl AnthropicModelInfo {
     /// Get pricing information for a given model ID
     pub fn get_pricing(model_id: &str) -> AnthropicModelPricing {
         match model_id {
+            // Claude 4 models
+            "claude-sonnet-4-20250514" | "claude-sonnet-4-5-20250929" => {
+                AnthropicModelPricing::new(3.00, 15.00).with_long_context(200000, 6.00, 22.50)
+            }
+
             // Claude 3.7 models
-            "claude-3-7-sonnet-20250219" => AnthropicModelPricing {
-                input_cost_per_million_tokens: 3.00,
-                output_cost_per_million_tokens: 15.00,
-            },
+            "claude-3-7-sonnet-20250219" => AnthropicModelPricing::new(3.00, 15.00),
 
             // Claude 3.5 models
-            "claude-3-5-sonnet-20241022" | "claude-3-5-sonnet-20240620" => AnthropicModelPricing {
-                input_cost_per_million_tokens: 3.00,
-                output_cost_per_million_tokens: 15.00,
-            },
-            "claude-3-5-haiku-20241022" => AnthropicModelPricing {
-                input_cost_per_million_tokens: 0.80,
-                output_cost_per_million_tokens: 4.00,
-            },
+            "claude-3-5-sonnet-20241022" | "claude-3-5-sonnet-20240620" => {
+                AnthropicModelPricing::new(3.00, 15.00)
+            }
+            "claude-3-5-haiku-20241022" => AnthropicModelPricing::new(0.80, 4.00),
 
             // Claude 3 models
-            "claude-3-opus-20240229" => AnthropicModelPricing {
-                input_cost_per_million_tokens: 15.00,
-                output_cost_per_million_tokens: 75.00,
-            },
-            "claude-3-haiku-20240307" => AnthropicModelPricing {
-                input_cost_per_million_tokens: 0.25,
-                output_cost_per_million_tokens: 1.25,
-            },
-            "claude-3-sonnet-20240229" => AnthropicModelPricing {
-                input_cost_per_million_tokens: 3.00,
-                output_cost_per_million_tokens: 15.00,
-            },
+            "claude-3-opus-20240229" => AnthropicModelPricing::new(15.00, 75.00),
+            "claude-3-haiku-20240307" => AnthropicModelPricing::new(0.25, 1.25),
+            "claude-3-sonnet-20240229" => AnthropicModelPricing::new(3.00, 15.00),
 
             // Default for older or unknown models
-            _ => AnthropicModelPricing {
-                input_cost_per_million_tokens: 8.00,
-                output_cost_per_million_tokens: 24.00,
-            },
+            _ => AnthropicModelPricing::new(8.00, 24.00),
         }
     }
 }
 
 /// Tool choice configuration
-#[derive(Serialize, Deserialize, Debug, Clone)]
+///
+/// `disable_parallel_tool_use` lives inside the tool choice object on the wire. The
+/// `none` choice does not accept it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "type")]
 pub enum AnthropicToolChoice {
     /// Model decides whether to use tools
     #[serde(rename = "auto")]
-    Auto,
+    Auto {
+        /// Use at most one tool
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        disable_parallel_tool_use: Option<bool>,
+    },
 
     /// Force model to use a specific tool
     #[serde(rename = "tool")]
     Tool {
         /// Name of the tool to use
         name: String,
+
+        /// Use exactly one tool
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        disable_parallel_tool_use: Option<bool>,
     },
 
     /// Force model to use any available tool
     #[serde(rename = "any")]
-    Any,
+    Any {
+        /// Use exactly one tool
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        disable_parallel_tool_use: Option<bool>,
+    },
 
     /// Force model not to use tools
     #[serde(rename = "none")]
@@ -490,9 +1153,9 @@ pub enum AnthropicToolChoice {
 impl From<ToolChoice> for AnthropicToolChoice {
     fn from(choice: ToolChoice) -> Self {
         match choice {
-            ToolChoice::Auto => Self::Auto,
-            ToolChoice::Tool { name } => Self::Tool { name },
-            ToolChoice::Any => Self::Any,
+            ToolChoice::Auto => Self::auto(),
+            ToolChoice::Tool { name } => Self::specific(name),
+            ToolChoice::Any => Self::any(),
             ToolChoice::None => Self::None,
         }
     }
@@ -501,23 +1164,50 @@ impl From<ToolChoice> for AnthropicToolChoice {
 impl AnthropicToolChoice {
     /// Create a new auto tool choice
     pub fn auto() -> Self {
-        Self::Auto
+        Self::Auto {
+            disable_parallel_tool_use: None,
+        }
     }
 
     /// Create a new tool-specific choice
     pub fn specific(name: impl Into<String>) -> Self {
-        Self::Tool { name: name.into() }
+        Self::Tool {
+            name: name.into(),
+            disable_parallel_tool_use: None,
+        }
     }
 
     /// Create a new any tool choice
     pub fn any() -> Self {
-        Self::Any
+        Self::Any {
+            disable_parallel_tool_use: None,
+        }
     }
 
     /// Create a new none tool choice
     pub fn none() -> Self {
         Self::None
     }
+
+    /// Set `disable_parallel_tool_use`, unless it is already set or the choice is `none`
+    pub fn with_default_parallel_tool_use(mut self, disable: bool) -> Self {
+        match &mut self {
+            Self::Auto {
+                disable_parallel_tool_use,
+            }
+            | Self::Tool {
+                disable_parallel_tool_use,
+                ..
+            }
+            | Self::Any {
+                disable_parallel_tool_use,
+            } => {
+                disable_parallel_tool_use.get_or_insert(disable);
+            }
+            Self::None => {}
+        }
+        self
+    }
 }
 
 use std::error::Error;
@@ -543,6 +1233,39 @@ pub enum AnthropicError {
 
     /// Authentication error
     AuthenticationError(String),
+
+    /// Circuit breaker is open after repeated upstream failures
+    CircuitOpen { retry_after_ms: u64 },
+
+    /// Caller has used up its budget
+    BudgetExceeded { caller: String, message: String },
+
+    /// Request does not fit in the model's context window
+    ContextWindowExceeded {
+        model: String,
+        input_tokens: u32,
+        max_tokens: u32,
+        usable_tokens: u32,
+        context_window: u32,
+    },
+
+    /// No session exists with the given ID
+    SessionNotFound { session_id: String },
+
+    /// Creating another session would exceed `sessions.max_sessions`
+    SessionLimitReached { max_sessions: usize },
+
+    /// The conversation history is malformed and cannot be repaired
+    InvalidMessages(String),
+
+    /// Structured output still did not match its schema after every retry
+    StructuredOutputInvalid {
+        attempts: u32,
+        violations: Vec<SchemaViolation>,
+    },
+
+    /// An MCP server actor could not be initialized or its tools could not be added
+    McpError { actor_id: String, message: String },
 }
 
 impl fmt::Display for AnthropicError {
@@ -562,6 +1285,60 @@ impl fmt::Display for AnthropicError {
                 }
             }
             AnthropicError::AuthenticationError(msg) => write!(f, "Authentication error: {}", msg),
+            AnthropicError::CircuitOpen { retry_after_ms } => write!(
+                f,
+                "Circuit breaker open after repeated upstream failures. Retry after {} ms",
+                retry_after_ms
+            ),
+            AnthropicError::BudgetExceeded { caller, message } => {
+                write!(f, "Budget exceeded for {}: {}", caller, message)
+            }
+            AnthropicError::ContextWindowExceeded {
+                model,
+                input_tokens,
+                max_tokens,
+                usable_tokens,
+                context_window,
+            } => write!(
+                f,
+                "Context window exceeded: ~{} input tokens + {} max_tokens = {} tokens, over the {} usable tokens of {}'s {}-token context window",
+                input_tokens,
+                max_tokens,
+                *input_tokens as u64 + *max_tokens as u64,
+                usable_tokens,
+                model,
+                context_window
+            ),
+            AnthropicError::SessionNotFound { session_id } => {
+                write!(f, "Session not found: {}", session_id)
+            }
+            AnthropicError::SessionLimitReached { max_sessions } => write!(
+                f,
+                "Session limit of {} reached; delete sessions or raise sessions.max_sessions",
+                max_sessions
+            ),
+            AnthropicError::InvalidMessages(msg) => write!(f, "Invalid messages: {}", msg),
+            AnthropicError::McpError { actor_id, message } => {
+                write!(f, "MCP actor {}: {}", actor_id, message)
+            }
+            AnthropicError::StructuredOutputInvalid {
+                attempts,
+                violations,
+            } => {
+                let violations: Vec<String> = violations
+                    .iter()
+                    .map(|v| {
+                        let path = if v.path.is_empty() { "/" } else { &v.path };
+                        format!("{}: {}", path, v.message)
+                    })
+                    .collect();
+                write!(
+                    f,
+                    "Structured output did not match the schema after {} attempts: {}",
+                    attempts,
+                    violations.join("; ")
+                )
+            }
         }
     }
 }
@@ -573,3 +1350,100 @@ impl From<serde_json::Error> for AnthropicError {
         AnthropicError::JsonError(error.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn usage(value: serde_json::Value) -> AnthropicUsage {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn assert_usd(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected ${}, got ${}",
+            expected,
+            actual
+        );
+    }
+
+    /// Claude Sonnet 4.5: $3/$15 per million, $6/$22.50 above 200k input tokens
+    fn sonnet() -> AnthropicModelPricing {
+        AnthropicModelInfo::get_pricing("claude-sonnet-4-5-20250929")
+    }
+
+    #[test]
+    fn cache_reads_and_writes_are_priced_from_the_input_rate() {
+        let cost = sonnet().cost(&usage(json!({
+            "input_tokens": 1_000,
+            "output_tokens": 500,
+            "cache_read_input_tokens": 10_000,
+            "cache_creation_input_tokens": 2_000,
+            "cache_creation": {
+                "ephemeral_5m_input_tokens": 1_000,
+                "ephemeral_1h_input_tokens": 1_000
+            }
+        })));
+        assert_usd(cost.input_usd, 0.003);
+        assert_usd(cost.output_usd, 0.0075);
+        // 5 minute writes at 1.25x ($3.75), 1 hour writes at 2x ($6)
+        assert_usd(cost.cache_write_usd, 0.00375 + 0.006);
+        // Reads at 0.1x ($0.30)
+        assert_usd(cost.cache_read_usd, 0.003);
+        assert_usd(cost.total_usd, 0.02325);
+        assert!(!cost.long_context);
+        assert!(!cost.batch_discount);
+
+        // Without the TTL breakdown every write is priced as a 5 minute write
+        let cost = sonnet().cost(&usage(json!({
+            "input_tokens": 0,
+            "output_tokens": 0,
+            "cache_creation_input_tokens": 2_000
+        })));
+        assert_usd(cost.cache_write_usd, 0.0075);
+    }
+
+    #[test]
+    fn long_prompts_use_the_long_context_tier() {
+        // Cache reads count towards the threshold: 150k + 60k input tokens in total
+        let cost = sonnet().cost(&usage(json!({
+            "input_tokens": 150_000,
+            "output_tokens": 1_000,
+            "cache_read_input_tokens": 60_000
+        })));
+        assert!(cost.long_context);
+        assert_usd(cost.input_usd, 0.9);
+        assert_usd(cost.output_usd, 0.0225);
+        // Cache prices scale with the input rate: $0.60 instead of $0.30
+        assert_usd(cost.cache_read_usd, 0.036);
+        assert_usd(cost.total_usd, 0.9585);
+
+        // Exactly at the threshold is still the base tier
+        let cost = sonnet().cost(&usage(json!({
+            "input_tokens": 200_000,
+            "output_tokens": 0
+        })));
+        assert!(!cost.long_context);
+        assert_usd(cost.total_usd, 0.6);
+    }
+
+    #[test]
+    fn batch_requests_are_half_price() {
+        let cost = sonnet().cost(&usage(json!({
+            "input_tokens": 1_000,
+            "output_tokens": 1_000,
+            "cache_read_input_tokens": 10_000,
+            "service_tier": "batch",
+            "server_tool_use": { "web_search_requests": 2 }
+        })));
+        assert!(cost.batch_discount);
+        assert_usd(cost.input_usd, 0.0015);
+        assert_usd(cost.output_usd, 0.0075);
+        assert_usd(cost.cache_read_usd, 0.0015);
+        // Web searches are charged per search, without the discount
+        assert_usd(cost.web_search_usd, 0.02);
+        assert_usd(cost.total_usd, 0.0305);
+    }
+}
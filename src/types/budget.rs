@@ -0,0 +1,379 @@
+use crate::types::api::{AnthropicCompletionRequest, AnthropicError, AnthropicUsage};
+use crate::types::time::{civil_from_days, DAY_MS};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Caller key used for budgets when a request does not name a caller
+pub const ANONYMOUS_CALLER: &str = "anonymous";
+
+/// What happens when a caller has used up its budget
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "mode")]
+pub enum BudgetEnforcement {
+    /// Reject further requests until the period rolls over
+    #[serde(rename = "reject")]
+    Reject,
+
+    /// Log a warning and let the request through
+    #[serde(rename = "warn")]
+    Warn,
+
+    /// Send the request to a cheaper model instead
+    #[serde(rename = "downgrade")]
+    Downgrade {
+        /// Model to use once the budget is exhausted
+        model: String,
+    },
+}
+
+/// Token and dollar limits for a single caller
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CallerBudget {
+    /// Maximum tokens (input + output) per UTC day
+    pub daily_tokens: Option<u64>,
+
+    /// Maximum tokens (input + output) per UTC calendar month
+    pub monthly_tokens: Option<u64>,
+
+    /// Maximum spend in dollars per UTC day
+    pub daily_cost_usd: Option<f64>,
+
+    /// Maximum spend in dollars per UTC calendar month
+    pub monthly_cost_usd: Option<f64>,
+
+    /// What to do once any of the limits is reached
+    pub enforcement: BudgetEnforcement,
+}
+
+/// Per-caller budget configuration
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct BudgetConfig {
+    /// Budgets keyed by caller key
+    pub callers: HashMap<String, CallerBudget>,
+
+    /// Budget for callers without their own entry
+    pub default: Option<CallerBudget>,
+}
+
+impl BudgetConfig {
+    /// Get the budget that applies to a caller
+    pub fn for_caller(&self, caller: &str) -> Option<&CallerBudget> {
+        self.callers.get(caller).or(self.default.as_ref())
+    }
+}
+
+/// Tokens and dollars spent within a period
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Spend {
+    pub tokens: u64,
+
+    pub cost_usd: f64,
+}
+
+impl Spend {
+    fn add(&mut self, tokens: u64, cost_usd: f64) {
+        self.tokens += tokens;
+        self.cost_usd += cost_usd;
+    }
+}
+
+/// Spend for a single caller in the current day and month
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CallerSpend {
+    /// Current UTC day, as days since the Unix epoch
+    pub day: u64,
+
+    /// Current UTC month, as `year * 12 + (month - 1)`
+    pub month: u64,
+
+    pub daily: Spend,
+
+    pub monthly: Spend,
+}
+
+impl CallerSpend {
+    /// Reset the counters for any period that has rolled over
+    fn roll_over(&mut self, now: u64) {
+        let day = now / DAY_MS;
+        let month = month_index(day);
+
+        if day != self.day {
+            self.day = day;
+            self.daily = Spend::default();
+        }
+        if month != self.month {
+            self.month = month;
+            self.monthly = Spend::default();
+        }
+    }
+
+    /// Describe the first limit of `budget` that has been reached, if any
+    fn exceeded(&self, budget: &CallerBudget) -> Option<String> {
+        let token_limits = [
+            ("daily", budget.daily_tokens, self.daily.tokens),
+            ("monthly", budget.monthly_tokens, self.monthly.tokens),
+        ];
+        for (period, limit, spent) in token_limits {
+            if let Some(limit) = limit {
+                if spent >= limit {
+                    return Some(format!(
+                        "{} token budget of {} reached ({} used)",
+                        period, limit, spent
+                    ));
+                }
+            }
+        }
+
+        let cost_limits = [
+            ("daily", budget.daily_cost_usd, self.daily.cost_usd),
+            ("monthly", budget.monthly_cost_usd, self.monthly.cost_usd),
+        ];
+        for (period, limit, spent) in cost_limits {
+            if let Some(limit) = limit {
+                if spent >= limit {
+                    return Some(format!(
+                        "{} spend budget of ${:.2} reached (${:.2} spent)",
+                        period, limit, spent
+                    ));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Ledger of spend per caller
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BudgetLedger {
+    /// Spend keyed by caller key
+    pub callers: HashMap<String, CallerSpend>,
+}
+
+impl BudgetLedger {
+    /// Check a caller against its budget at `now`.
+    ///
+    /// Returns a description of the exceeded limit if the caller is over budget.
+    pub fn check(&mut self, caller: &str, budget: &CallerBudget, now: u64) -> Option<String> {
+        let spend = self.callers.entry(caller.to_string()).or_default();
+        spend.roll_over(now);
+        spend.exceeded(budget)
+    }
+
+    /// Check a request against its caller's budget at `now` and apply the budget's
+    /// enforcement mode.
+    ///
+    /// An over-budget request fails with `BudgetExceeded` under `reject`, and has its
+    /// model replaced under `downgrade`. When the request goes ahead over budget, the
+    /// returned note says why, for the caller to log.
+    pub fn enforce(
+        &mut self,
+        config: &BudgetConfig,
+        caller: &str,
+        request: &mut AnthropicCompletionRequest,
+        now: u64,
+    ) -> Result<Option<String>, AnthropicError> {
+        let Some(budget) = config.for_caller(caller) else {
+            return Ok(None);
+        };
+        let Some(message) = self.check(caller, budget, now) else {
+            return Ok(None);
+        };
+
+        match &budget.enforcement {
+            BudgetEnforcement::Reject => Err(AnthropicError::BudgetExceeded {
+                caller: caller.to_string(),
+                message,
+            }),
+            BudgetEnforcement::Warn => Ok(Some(format!(
+                "WARNING: {} is over budget: {}",
+                caller, message
+            ))),
+            BudgetEnforcement::Downgrade { model } => {
+                let note = format!(
+                    "{} is over budget ({}), downgrading from {} to {}",
+                    caller, message, request.model, model
+                );
+                request.model = model.clone();
+                Ok(Some(note))
+            }
+        }
+    }
+
+    /// Record the usage and cost of a completed request
+    pub fn record(&mut self, caller: &str, usage: &AnthropicUsage, cost_usd: f64, now: u64) {
+        let tokens = usage.input_tokens as u64
+            + usage.output_tokens as u64
+            + usage.cache_creation_input_tokens.unwrap_or(0) as u64
+            + usage.cache_read_input_tokens.unwrap_or(0) as u64;
+
+        let spend = self.callers.entry(caller.to_string()).or_default();
+        spend.roll_over(now);
+        spend.daily.add(tokens, cost_usd);
+        spend.monthly.add(tokens, cost_usd);
+    }
+}
+
+/// Calendar month index (`year * 12 + (month - 1)`) for a day since the Unix epoch
+fn month_index(days: u64) -> u64 {
+    let (year, month, _) = civil_from_days(days as i64);
+    (year * 12 + month - 1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::time::days_from_civil;
+    use serde_json::json;
+
+    /// Noon UTC on a date, in milliseconds since the epoch
+    fn noon(year: i64, month: i64, day: i64) -> u64 {
+        days_from_civil(year, month, day) as u64 * DAY_MS + DAY_MS / 2
+    }
+
+    fn usage(tokens: u32) -> AnthropicUsage {
+        AnthropicUsage {
+            input_tokens: tokens,
+            ..Default::default()
+        }
+    }
+
+    fn budget(enforcement: BudgetEnforcement) -> CallerBudget {
+        CallerBudget {
+            daily_tokens: Some(1000),
+            monthly_tokens: None,
+            daily_cost_usd: None,
+            monthly_cost_usd: Some(10.0),
+            enforcement,
+        }
+    }
+
+    fn config(enforcement: BudgetEnforcement) -> BudgetConfig {
+        BudgetConfig {
+            callers: HashMap::from([("tenant-a".to_string(), budget(enforcement))]),
+            default: None,
+        }
+    }
+
+    fn request() -> AnthropicCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [{ "role": "user", "content": [{ "type": "text", "text": "Hello" }] }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn month_index_crosses_the_year() {
+        let december = days_from_civil(2024, 12, 31) as u64;
+        assert_eq!(month_index(december), 2024 * 12 + 11);
+        assert_eq!(month_index(december + 1), 2025 * 12);
+    }
+
+    #[test]
+    fn periods_roll_over() {
+        let mut ledger = BudgetLedger::default();
+        ledger.record("tenant-a", &usage(600), 2.0, noon(2024, 12, 30));
+        ledger.record("tenant-a", &usage(400), 3.0, noon(2024, 12, 30));
+
+        // The next day only resets the daily counters
+        let budget = budget(BudgetEnforcement::Reject);
+        assert!(ledger
+            .check("tenant-a", &budget, noon(2024, 12, 30))
+            .is_some());
+        assert!(ledger
+            .check("tenant-a", &budget, noon(2024, 12, 31))
+            .is_none());
+        let spend = &ledger.callers["tenant-a"];
+        assert_eq!(spend.daily.tokens, 0);
+        assert_eq!(spend.monthly.tokens, 1000);
+        assert_eq!(spend.monthly.cost_usd, 5.0);
+
+        // The new year starts a new month too
+        ledger.check("tenant-a", &budget, noon(2025, 1, 1));
+        let spend = &ledger.callers["tenant-a"];
+        assert_eq!(spend.month, 2025 * 12);
+        assert_eq!(spend.monthly.tokens, 0);
+        assert_eq!(spend.monthly.cost_usd, 0.0);
+    }
+
+    #[test]
+    fn reject_blocks_once_over_budget() {
+        let config = config(BudgetEnforcement::Reject);
+        let mut ledger = BudgetLedger::default();
+        let now = noon(2025, 3, 14);
+
+        ledger.record("tenant-a", &usage(999), 0.5, now);
+        assert_eq!(
+            ledger
+                .enforce(&config, "tenant-a", &mut request(), now)
+                .unwrap(),
+            None
+        );
+
+        ledger.record("tenant-a", &usage(1), 0.5, now);
+        let error = ledger
+            .enforce(&config, "tenant-a", &mut request(), now)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Budget exceeded for tenant-a: daily token budget of 1000 reached (1000 used)"
+        );
+
+        // Callers without a budget are not limited
+        ledger.record("tenant-b", &usage(5000), 50.0, now);
+        assert_eq!(
+            ledger
+                .enforce(&config, "tenant-b", &mut request(), now)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn warn_lets_the_request_through() {
+        let config = config(BudgetEnforcement::Warn);
+        let mut ledger = BudgetLedger::default();
+        let now = noon(2025, 3, 14);
+        ledger.record("tenant-a", &usage(10), 12.0, now);
+
+        let mut request = request();
+        let note = ledger
+            .enforce(&config, "tenant-a", &mut request, now)
+            .unwrap();
+        assert_eq!(
+            note.as_deref(),
+            Some("WARNING: tenant-a is over budget: monthly spend budget of $10.00 reached ($12.00 spent)")
+        );
+        assert_eq!(request.model, "claude-sonnet-4-5-20250929");
+    }
+
+    #[test]
+    fn downgrade_rewrites_the_model() {
+        let config = config(BudgetEnforcement::Downgrade {
+            model: "claude-3-5-haiku-20241022".to_string(),
+        });
+        let mut ledger = BudgetLedger::default();
+        let now = noon(2025, 3, 14);
+
+        let mut request = request();
+        assert_eq!(
+            ledger
+                .enforce(&config, "tenant-a", &mut request, now)
+                .unwrap(),
+            None
+        );
+        assert_eq!(request.model, "claude-sonnet-4-5-20250929");
+
+        ledger.record("tenant-a", &usage(1500), 1.0, now);
+        let note = ledger
+            .enforce(&config, "tenant-a", &mut request, now)
+            .unwrap();
+        assert!(note
+            .unwrap()
+            .contains("downgrading from claude-sonnet-4-5-20250929 to claude-3-5-haiku-20241022"));
+        assert_eq!(request.model, "claude-3-5-haiku-20241022");
+    }
+}
@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+
+/// Configuration for the upstream circuit breaker
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive upstream failures (5xx/529 or transport errors) before the circuit opens
+    pub failure_threshold: u32,
+
+    /// How long the circuit stays open before a probe request is allowed, in milliseconds
+    pub cooldown_ms: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown_ms: 30000, // 30 seconds
+        }
+    }
+}
+
+/// Current position of the circuit breaker
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CircuitState {
+    /// Requests flow normally
+    Closed,
+
+    /// Requests fail immediately until the cooldown has elapsed
+    Open { opened_at: u64 },
+
+    /// A single probe request is allowed through to test the upstream
+    HalfOpen,
+}
+
+/// Circuit breaker tracking the health of the upstream API across requests
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CircuitBreaker {
+    /// Current circuit state
+    pub state: CircuitState,
+
+    /// Number of upstream failures seen since the last healthy response
+    pub consecutive_failures: u32,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// Check whether a request may be sent at `now`.
+    ///
+    /// Moves an open circuit to half-open once the cooldown has elapsed. Returns the
+    /// remaining cooldown in milliseconds if the circuit is still open.
+    pub fn check(&mut self, now: u64, config: &CircuitBreakerConfig) -> Result<(), u64> {
+        if let CircuitState::Open { opened_at } = self.state {
+            let elapsed = now.saturating_sub(opened_at);
+            if elapsed < config.cooldown_ms {
+                return Err(config.cooldown_ms - elapsed);
+            }
+            self.state = CircuitState::HalfOpen;
+        }
+        Ok(())
+    }
+
+    /// Whether the next request is a probe
+    pub fn is_half_open(&self) -> bool {
+        self.state == CircuitState::HalfOpen
+    }
+
+    /// Record a healthy response from the upstream, closing the circuit
+    pub fn record_success(&mut self) {
+        self.state = CircuitState::Closed;
+        self.consecutive_failures = 0;
+    }
+
+    /// Record an upstream failure. Returns true if this failure opened the circuit.
+    pub fn record_failure(&mut self, now: u64, config: &CircuitBreakerConfig) -> bool {
+        self.consecutive_failures += 1;
+
+        if self.is_half_open() || self.consecutive_failures >= config.failure_threshold {
+            self.state = CircuitState::Open { opened_at: now };
+            return true;
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            failure_threshold: 3,
+            cooldown_ms: 1000,
+        }
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let mut circuit = CircuitBreaker::default();
+        assert!(!circuit.record_failure(10, &config()));
+        assert!(!circuit.record_failure(20, &config()));
+        assert!(circuit.record_failure(30, &config()));
+        assert_eq!(circuit.state, CircuitState::Open { opened_at: 30 });
+
+        // Rejected for the rest of the cooldown
+        assert_eq!(circuit.check(530, &config()), Err(500));
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let mut circuit = CircuitBreaker::default();
+        circuit.record_failure(10, &config());
+        circuit.record_failure(20, &config());
+        circuit.record_success();
+        assert!(!circuit.record_failure(30, &config()));
+        assert_eq!(circuit.state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn half_open_probe_closes_or_reopens() {
+        let mut circuit = CircuitBreaker {
+            state: CircuitState::Open { opened_at: 0 },
+            consecutive_failures: 3,
+        };
+
+        assert_eq!(circuit.check(1000, &config()), Ok(()));
+        assert!(circuit.is_half_open());
+        circuit.record_success();
+        assert_eq!(circuit.state, CircuitState::Closed);
+        assert_eq!(circuit.consecutive_failures, 0);
+
+        // A failed probe opens the circuit again straight away
+        let mut circuit = CircuitBreaker {
+            state: CircuitState::HalfOpen,
+            consecutive_failures: 3,
+        };
+        assert!(circuit.record_failure(5000, &config()));
+        assert_eq!(circuit.state, CircuitState::Open { opened_at: 5000 });
+        assert_eq!(circuit.check(5999, &config()), Err(1));
+    }
+}
@@ -1,2 +1,9 @@
 pub mod api;
+pub mod budget;
+pub mod circuit;
+pub mod rate_limit;
+pub mod session;
 pub mod state;
+pub mod time;
+pub mod tools;
+pub mod usage;
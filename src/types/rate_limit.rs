@@ -0,0 +1,503 @@
+use crate::tokens::estimator;
+use crate::types::api::{AnthropicCompletionRequest, AnthropicUsage};
+use crate::types::time::parse_rfc3339_ms;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Milliseconds over which a per-minute limit refills completely
+const REFILL_WINDOW_MS: f64 = 60000.0;
+
+/// Client-side rate limits for a single model
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModelRateLimit {
+    /// Maximum requests per minute
+    pub requests_per_minute: Option<u32>,
+
+    /// Maximum input tokens per minute
+    pub input_tokens_per_minute: Option<u32>,
+
+    /// Maximum output tokens per minute
+    pub output_tokens_per_minute: Option<u32>,
+}
+
+/// Client-side rate limiting configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Longest a request will wait for capacity before being rejected, in milliseconds
+    pub max_wait_ms: u64,
+
+    /// Limits keyed by model ID. Models without an entry are not limited.
+    pub models: HashMap<String, ModelRateLimit>,
+
+    /// Whether to pace requests using the rate limit headers returned by Anthropic
+    pub adaptive: bool,
+
+    /// Fraction of an upstream limit below which requests are spread out until the reset
+    pub slowdown_threshold: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_wait_ms: 10000, // 10 seconds
+            models: HashMap::new(),
+            adaptive: true,
+            slowdown_threshold: 0.1, // Slow down below 10% remaining
+        }
+    }
+}
+
+/// Up-front token estimate for a request, reconciled with actual usage afterwards
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TokenEstimate {
+    pub input_tokens: u32,
+
+    pub output_tokens: u32,
+}
+
+impl TokenEstimate {
+    /// Estimate the tokens a completion request will consume.
+    ///
+    /// Input uses the offline estimator; output assumes the worst case of `max_tokens`.
+    pub fn for_request(request: &AnthropicCompletionRequest) -> Self {
+        Self {
+            input_tokens: estimator::estimate_request(request),
+            output_tokens: request.max_tokens,
+        }
+    }
+}
+
+/// A token bucket that refills linearly over one minute
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens currently available. May go negative when a request exceeds the capacity.
+    pub tokens: f64,
+
+    /// Timestamp of the last refill in milliseconds
+    pub last_refill: u64,
+}
+
+impl TokenBucket {
+    fn new(capacity: u32, now: u64) -> Self {
+        Self {
+            tokens: capacity as f64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, capacity: u32, now: u64) {
+        let elapsed = now.saturating_sub(self.last_refill) as f64;
+        let capacity = capacity as f64;
+        self.tokens = (self.tokens + elapsed * capacity / REFILL_WINDOW_MS).min(capacity);
+        self.last_refill = now;
+    }
+
+    /// Milliseconds until `amount` tokens are available.
+    ///
+    /// Amounts larger than the capacity only need a full bucket, so oversized
+    /// requests are delayed rather than blocked forever.
+    fn wait_time(&self, capacity: u32, amount: u32) -> u64 {
+        let needed = (amount.min(capacity) as f64) - self.tokens;
+        if needed <= 0.0 || capacity == 0 {
+            return 0;
+        }
+        (needed * REFILL_WINDOW_MS / capacity as f64).ceil() as u64
+    }
+}
+
+/// Token buckets for a single model
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModelBuckets {
+    pub requests: Option<TokenBucket>,
+
+    pub input_tokens: Option<TokenBucket>,
+
+    pub output_tokens: Option<TokenBucket>,
+}
+
+/// Per-model client-side rate limiter state
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateLimiter {
+    /// Buckets keyed by model ID
+    pub buckets: HashMap<String, ModelBuckets>,
+}
+
+impl RateLimiter {
+    fn buckets_for(&mut self, model: &str, limits: &ModelRateLimit, now: u64) -> &mut ModelBuckets {
+        let buckets = self.buckets.entry(model.to_string()).or_default();
+
+        for (bucket, capacity) in [
+            (&mut buckets.requests, limits.requests_per_minute),
+            (&mut buckets.input_tokens, limits.input_tokens_per_minute),
+            (&mut buckets.output_tokens, limits.output_tokens_per_minute),
+        ] {
+            match capacity {
+                Some(capacity) => bucket
+                    .get_or_insert_with(|| TokenBucket::new(capacity, now))
+                    .refill(capacity, now),
+                None => *bucket = None,
+            }
+        }
+
+        buckets
+    }
+
+    /// Milliseconds until a request with the given estimate fits within the limits
+    pub fn wait_time(
+        &mut self,
+        model: &str,
+        limits: &ModelRateLimit,
+        estimate: &TokenEstimate,
+        now: u64,
+    ) -> u64 {
+        let buckets = self.buckets_for(model, limits, now);
+
+        [
+            (&buckets.requests, limits.requests_per_minute, 1),
+            (
+                &buckets.input_tokens,
+                limits.input_tokens_per_minute,
+                estimate.input_tokens,
+            ),
+            (
+                &buckets.output_tokens,
+                limits.output_tokens_per_minute,
+                estimate.output_tokens,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(bucket, capacity, amount)| {
+            Some(bucket.as_ref()?.wait_time(capacity?, amount))
+        })
+        .max()
+        .unwrap_or(0)
+    }
+
+    /// Take capacity for a request with the given estimate
+    pub fn acquire(
+        &mut self,
+        model: &str,
+        limits: &ModelRateLimit,
+        estimate: &TokenEstimate,
+        now: u64,
+    ) {
+        let buckets = self.buckets_for(model, limits, now);
+
+        if let Some(bucket) = buckets.requests.as_mut() {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(bucket) = buckets.input_tokens.as_mut() {
+            bucket.tokens -= estimate.input_tokens as f64;
+        }
+        if let Some(bucket) = buckets.output_tokens.as_mut() {
+            bucket.tokens -= estimate.output_tokens as f64;
+        }
+    }
+
+    /// Replace the up-front estimate with the actual usage, returning the difference to the buckets.
+    ///
+    /// Pass `None` for failed requests so that their token estimate is refunded.
+    pub fn reconcile(
+        &mut self,
+        model: &str,
+        estimate: &TokenEstimate,
+        usage: Option<&AnthropicUsage>,
+    ) {
+        let Some(buckets) = self.buckets.get_mut(model) else {
+            return;
+        };
+
+        let (input_tokens, output_tokens) = match usage {
+            Some(usage) => (
+                usage.input_tokens + usage.cache_creation_input_tokens.unwrap_or(0),
+                usage.output_tokens,
+            ),
+            None => (0, 0),
+        };
+
+        if let Some(bucket) = buckets.input_tokens.as_mut() {
+            bucket.tokens += estimate.input_tokens as f64 - input_tokens as f64;
+        }
+        if let Some(bucket) = buckets.output_tokens.as_mut() {
+            bucket.tokens += estimate.output_tokens as f64 - output_tokens as f64;
+        }
+    }
+}
+
+/// One rate limit window reported by Anthropic in the response headers
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateLimitWindow {
+    /// Maximum allowed within the window
+    pub limit: Option<u64>,
+
+    /// Amount remaining before the limit is reached
+    pub remaining: Option<u64>,
+
+    /// When the window resets, as reported (RFC 3339)
+    pub reset: Option<String>,
+
+    /// When the window resets, in milliseconds since the Unix epoch
+    pub reset_at: Option<u64>,
+}
+
+impl RateLimitWindow {
+    fn update(&mut self, headers: &[(String, String)], prefix: &str) {
+        let limit = header_value(headers, &format!("{}-limit", prefix));
+        let remaining = header_value(headers, &format!("{}-remaining", prefix));
+        let reset = header_value(headers, &format!("{}-reset", prefix));
+
+        if limit.is_none() && remaining.is_none() && reset.is_none() {
+            return;
+        }
+
+        self.limit = limit.and_then(|v| v.parse().ok());
+        self.remaining = remaining.and_then(|v| v.parse().ok());
+        self.reset_at = reset.and_then(parse_rfc3339_ms);
+        self.reset = reset.map(|v| v.to_string());
+    }
+
+    /// Milliseconds to wait before spending `needed` from this window.
+    ///
+    /// Waits for the reset when the window cannot cover the request, and spreads the
+    /// remaining budget evenly over the time left once it drops below `threshold`.
+    fn pacing_delay(&self, needed: u64, threshold: f64, now: u64) -> u64 {
+        let (Some(remaining), Some(reset_at)) = (self.remaining, self.reset_at) else {
+            return 0;
+        };
+        if reset_at <= now {
+            return 0;
+        }
+
+        let until_reset = reset_at - now;
+        if remaining < needed {
+            return until_reset;
+        }
+
+        match self.limit {
+            Some(limit) if limit > 0 && (remaining as f64) < limit as f64 * threshold => {
+                let batches = (remaining / needed.max(1)).max(1);
+                until_reset / batches
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Live view of the upstream rate limits for a model, learned from response headers
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UpstreamRateLimits {
+    pub requests: RateLimitWindow,
+
+    pub tokens: RateLimitWindow,
+
+    pub input_tokens: RateLimitWindow,
+
+    pub output_tokens: RateLimitWindow,
+
+    /// Seconds to wait from the last `retry-after` header
+    pub retry_after: Option<u64>,
+
+    /// When the headers were last seen, in milliseconds since the Unix epoch
+    pub updated_at: u64,
+}
+
+impl UpstreamRateLimits {
+    /// Update the picture from the headers of an API response
+    pub fn update(&mut self, headers: &[(String, String)], now: u64) {
+        self.requests
+            .update(headers, "anthropic-ratelimit-requests");
+        self.tokens.update(headers, "anthropic-ratelimit-tokens");
+        self.input_tokens
+            .update(headers, "anthropic-ratelimit-input-tokens");
+        self.output_tokens
+            .update(headers, "anthropic-ratelimit-output-tokens");
+        self.retry_after = header_value(headers, "retry-after").and_then(|v| v.parse().ok());
+        self.updated_at = now;
+    }
+
+    /// Milliseconds to wait before sending a request with the given estimate
+    pub fn pacing_delay(&self, estimate: &TokenEstimate, threshold: f64, now: u64) -> u64 {
+        let input = estimate.input_tokens as u64;
+        let output = estimate.output_tokens as u64;
+
+        [
+            self.requests.pacing_delay(1, threshold, now),
+            self.tokens.pacing_delay(input + output, threshold, now),
+            self.input_tokens.pacing_delay(input, threshold, now),
+            self.output_tokens.pacing_delay(output, threshold, now),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+    }
+}
+
+/// Look up a header value by case-insensitive name
+pub fn header_value<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ModelRateLimit {
+        ModelRateLimit {
+            requests_per_minute: Some(2),
+            input_tokens_per_minute: Some(6000),
+            output_tokens_per_minute: None,
+        }
+    }
+
+    fn estimate(input_tokens: u32) -> TokenEstimate {
+        TokenEstimate {
+            input_tokens,
+            output_tokens: 500,
+        }
+    }
+
+    #[test]
+    fn waits_for_the_bucket_to_refill() {
+        let mut limiter = RateLimiter::default();
+        let model = "claude-sonnet-4-5-20250929";
+
+        assert_eq!(limiter.wait_time(model, &limits(), &estimate(4000), 0), 0);
+        limiter.acquire(model, &limits(), &estimate(4000), 0);
+
+        // 2000 tokens left; 1000 more refill in 10 seconds at 6000 per minute
+        assert_eq!(
+            limiter.wait_time(model, &limits(), &estimate(3000), 0),
+            10000
+        );
+        assert_eq!(
+            limiter.wait_time(model, &limits(), &estimate(3000), 10000),
+            0
+        );
+
+        // Unlimited output is never waited on
+        let large_output = TokenEstimate {
+            input_tokens: 0,
+            output_tokens: 1_000_000,
+        };
+        assert_eq!(limiter.wait_time(model, &limits(), &large_output, 10000), 0);
+    }
+
+    #[test]
+    fn request_bucket_limits_the_request_rate() {
+        let mut limiter = RateLimiter::default();
+        let model = "claude-sonnet-4-5-20250929";
+
+        limiter.acquire(model, &limits(), &estimate(10), 0);
+        limiter.acquire(model, &limits(), &estimate(10), 0);
+
+        // One request refills every 30 seconds at 2 per minute
+        assert_eq!(limiter.wait_time(model, &limits(), &estimate(10), 0), 30000);
+        assert_eq!(
+            limiter.wait_time(model, &limits(), &estimate(10), 15000),
+            15000
+        );
+    }
+
+    #[test]
+    fn oversized_requests_only_wait_for_a_full_bucket() {
+        let mut limiter = RateLimiter::default();
+        let model = "claude-sonnet-4-5-20250929";
+
+        limiter.acquire(model, &limits(), &estimate(3000), 0);
+        // Needs the full 6000, which is 30 seconds away from 3000
+        assert_eq!(
+            limiter.wait_time(model, &limits(), &estimate(50000), 0),
+            30000
+        );
+    }
+
+    #[test]
+    fn reconcile_returns_the_difference_to_the_estimate() {
+        let mut limiter = RateLimiter::default();
+        let model = "claude-sonnet-4-5-20250929";
+
+        limiter.acquire(model, &limits(), &estimate(5000), 0);
+        let usage = AnthropicUsage {
+            input_tokens: 1000,
+            cache_creation_input_tokens: Some(500),
+            ..Default::default()
+        };
+        limiter.reconcile(model, &estimate(5000), Some(&usage));
+        let bucket = limiter.buckets[model].input_tokens.as_ref().unwrap();
+        assert_eq!(bucket.tokens, 4500.0);
+
+        // A failed request refunds its whole estimate
+        limiter.acquire(model, &limits(), &estimate(4000), 0);
+        limiter.reconcile(model, &estimate(4000), None);
+        let bucket = limiter.buckets[model].input_tokens.as_ref().unwrap();
+        assert_eq!(bucket.tokens, 4500.0);
+    }
+
+    fn headers(remaining: u64, reset: &str) -> Vec<(String, String)> {
+        vec![
+            (
+                "anthropic-ratelimit-input-tokens-limit".to_string(),
+                "10000".to_string(),
+            ),
+            (
+                "Anthropic-RateLimit-Input-Tokens-Remaining".to_string(),
+                remaining.to_string(),
+            ),
+            (
+                "anthropic-ratelimit-input-tokens-reset".to_string(),
+                reset.to_string(),
+            ),
+            ("retry-after".to_string(), " 7 ".to_string()),
+        ]
+    }
+
+    // 2025-01-01T00:01:00Z
+    const RESET_AT: u64 = 1_735_689_660_000;
+
+    #[test]
+    fn headers_update_the_windows() {
+        let mut upstream = UpstreamRateLimits::default();
+        upstream.update(&headers(4000, "2025-01-01T00:01:00Z"), 5);
+
+        assert_eq!(upstream.input_tokens.limit, Some(10000));
+        assert_eq!(upstream.input_tokens.remaining, Some(4000));
+        assert_eq!(upstream.input_tokens.reset_at, Some(RESET_AT));
+        assert_eq!(upstream.retry_after, Some(7));
+        assert_eq!(upstream.updated_at, 5);
+
+        // Windows missing from a response keep what they had
+        assert_eq!(upstream.requests.remaining, None);
+        upstream.update(&[], 6);
+        assert_eq!(upstream.input_tokens.remaining, Some(4000));
+        assert_eq!(upstream.retry_after, None);
+    }
+
+    #[test]
+    fn pacing_spreads_the_remaining_budget_until_the_reset() {
+        let mut upstream = UpstreamRateLimits::default();
+        let now = RESET_AT - 30000;
+        let request = TokenEstimate {
+            input_tokens: 200,
+            output_tokens: 0,
+        };
+
+        // Plenty left: no delay
+        upstream.update(&headers(4000, "2025-01-01T00:01:00Z"), now);
+        assert_eq!(upstream.pacing_delay(&request, 0.1, now), 0);
+
+        // Below 10%: 800 tokens cover 4 requests of 200 over the 30 seconds left
+        upstream.update(&headers(800, "2025-01-01T00:01:00Z"), now);
+        assert_eq!(upstream.pacing_delay(&request, 0.1, now), 7500);
+
+        // Not enough for the request: wait for the reset
+        upstream.update(&headers(100, "2025-01-01T00:01:00Z"), now);
+        assert_eq!(upstream.pacing_delay(&request, 0.1, now), 30000);
+
+        // Once the reset has passed the window no longer applies
+        assert_eq!(upstream.pacing_delay(&request, 0.1, RESET_AT), 0);
+    }
+}
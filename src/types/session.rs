@@ -0,0 +1,201 @@
+use crate::types::api::{AnthropicCompletionRequest, AnthropicError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Session storage configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Maximum number of sessions to keep. Creating or forking a session beyond it
+    /// fails until sessions are deleted or expire.
+    pub max_sessions: Option<usize>,
+
+    /// Sessions left unchanged for this long, in milliseconds, expire and are removed
+    pub idle_ttl_ms: Option<u64>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            max_sessions: Some(1000),
+            idle_ttl_ms: None,
+        }
+    }
+}
+
+/// A conversation whose history is held by the proxy
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    /// Session ID
+    pub id: String,
+
+    /// Request settings for every turn; `messages` holds the full history
+    pub request: AnthropicCompletionRequest,
+
+    /// Caller key used for budgets and the usage ledger
+    pub caller: Option<String>,
+
+    /// Session this one was forked from
+    pub parent_id: Option<String>,
+
+    /// When the session was created, in milliseconds since the Unix epoch
+    pub created_at: u64,
+
+    /// When the session was last changed, in milliseconds since the Unix epoch
+    pub updated_at: u64,
+}
+
+/// All sessions, keyed by ID
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionStore {
+    pub sessions: HashMap<String, Session>,
+
+    /// Counter used to generate session IDs
+    pub next_id: u64,
+}
+
+impl SessionStore {
+    /// Create a session and return a copy of it
+    pub fn create(
+        &mut self,
+        request: AnthropicCompletionRequest,
+        caller: Option<String>,
+        parent_id: Option<String>,
+        now: u64,
+        config: &SessionConfig,
+    ) -> Result<Session, AnthropicError> {
+        if let Some(max_sessions) = config.max_sessions {
+            if self.sessions.len() >= max_sessions {
+                return Err(AnthropicError::SessionLimitReached { max_sessions });
+            }
+        }
+
+        self.next_id += 1;
+        let session = Session {
+            id: format!("session-{}-{}", now, self.next_id),
+            request,
+            caller,
+            parent_id,
+            created_at: now,
+            updated_at: now,
+        };
+
+        self.sessions.insert(session.id.clone(), session.clone());
+        Ok(session)
+    }
+
+    /// Look up a session
+    pub fn get(&self, session_id: &str) -> Result<&Session, AnthropicError> {
+        self.sessions
+            .get(session_id)
+            .ok_or_else(|| AnthropicError::SessionNotFound {
+                session_id: session_id.to_string(),
+            })
+    }
+
+    /// Look up a session for modification
+    pub fn get_mut(&mut self, session_id: &str) -> Result<&mut Session, AnthropicError> {
+        self.sessions
+            .get_mut(session_id)
+            .ok_or_else(|| AnthropicError::SessionNotFound {
+                session_id: session_id.to_string(),
+            })
+    }
+
+    /// Copy a session, including its history, into a new session
+    pub fn fork(
+        &mut self,
+        session_id: &str,
+        now: u64,
+        config: &SessionConfig,
+    ) -> Result<Session, AnthropicError> {
+        let parent = self.get(session_id)?.clone();
+        self.create(parent.request, parent.caller, Some(parent.id), now, config)
+    }
+
+    /// Remove a session
+    pub fn delete(&mut self, session_id: &str) -> Result<(), AnthropicError> {
+        self.sessions
+            .remove(session_id)
+            .map(|_| ())
+            .ok_or_else(|| AnthropicError::SessionNotFound {
+                session_id: session_id.to_string(),
+            })
+    }
+
+    /// Remove the sessions idle for longer than the configured TTL.
+    ///
+    /// Returns the IDs of the expired sessions.
+    pub fn expire(&mut self, now: u64, config: &SessionConfig) -> Vec<String> {
+        let Some(ttl) = config.idle_ttl_ms else {
+            return Vec::new();
+        };
+
+        let mut expired: Vec<String> = self
+            .sessions
+            .values()
+            .filter(|session| now.saturating_sub(session.updated_at) > ttl)
+            .map(|session| session.id.clone())
+            .collect();
+        expired.sort();
+        for id in &expired {
+            self.sessions.remove(id);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request() -> AnthropicCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4-5-20250929",
+            "max_tokens": 1024,
+            "messages": [
+                { "role": "user", "content": [{ "type": "text", "text": "Hello" }] }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn creating_beyond_the_limit_fails() {
+        let config = SessionConfig {
+            max_sessions: Some(2),
+            idle_ttl_ms: None,
+        };
+        let mut store = SessionStore::default();
+        let first = store.create(request(), None, None, 1_000, &config).unwrap();
+        store.create(request(), None, None, 2_000, &config).unwrap();
+
+        assert!(matches!(
+            store.create(request(), None, None, 3_000, &config),
+            Err(AnthropicError::SessionLimitReached { max_sessions: 2 })
+        ));
+        assert!(store.fork(&first.id, 3_000, &config).is_err());
+        assert_eq!(store.sessions.len(), 2);
+
+        store.delete(&first.id).unwrap();
+        assert!(store.create(request(), None, None, 4_000, &config).is_ok());
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let config = SessionConfig {
+            max_sessions: None,
+            idle_ttl_ms: Some(60_000),
+        };
+        let mut store = SessionStore::default();
+        let stale = store.create(request(), None, None, 1_000, &config).unwrap();
+        let active = store.create(request(), None, None, 1_000, &config).unwrap();
+        store.get_mut(&active.id).unwrap().updated_at = 50_000;
+
+        assert!(store.expire(61_000, &config).is_empty());
+        assert_eq!(store.expire(61_001, &config), vec![stale.id.clone()]);
+        assert!(store.get(&stale.id).is_err());
+        assert!(store.get(&active.id).is_ok());
+    }
+}
@@ -1,4 +1,18 @@
+use crate::agent::runner::AgentConfig;
+use crate::context::caching::{PromptCacheStats, PromptCachingConfig};
+use crate::context::compaction::{CompactionConfig, SummaryCache};
+use crate::context::continuation::ContinuationConfig;
+use crate::context::prefill::PrefillConfig;
+use crate::context::normalize::NormalizationConfig;
+use crate::context::window::ContextWindowConfig;
+use crate::schema::tool_input::ToolValidationConfig;
+use crate::types::budget::{BudgetConfig, BudgetLedger};
+use crate::types::circuit::{CircuitBreaker, CircuitBreakerConfig};
+use crate::types::rate_limit::{RateLimitConfig, RateLimiter, UpstreamRateLimits};
+use crate::types::session::{SessionConfig, SessionStore};
+use crate::types::usage::{UsageLedger, UsageLedgerConfig};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// Retry configuration for API requests
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -45,6 +59,58 @@ pub struct Config {
     
     /// Retry configuration for failed requests
     pub retry_config: RetryConfig,
+
+    /// Circuit breaker configuration for upstream failures
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// Client-side per-model rate limits
+    #[serde(default)]
+    pub rate_limits: RateLimitConfig,
+
+    /// Per-caller token and spend budgets
+    #[serde(default)]
+    pub budgets: BudgetConfig,
+
+    /// Usage ledger configuration
+    #[serde(default)]
+    pub usage_ledger: UsageLedgerConfig,
+
+    /// Context window management
+    #[serde(default)]
+    pub context_window: ContextWindowConfig,
+
+    /// Summarization of older turns in long conversations
+    #[serde(default)]
+    pub compaction: CompactionConfig,
+
+    /// Conversation session storage
+    #[serde(default)]
+    pub sessions: SessionConfig,
+
+    /// Automatic prompt cache breakpoints
+    #[serde(default)]
+    pub prompt_caching: PromptCachingConfig,
+
+    /// Message normalization and repair
+    #[serde(default)]
+    pub normalization: NormalizationConfig,
+
+    /// Validation of tool inputs returned by the model
+    #[serde(default)]
+    pub tool_validation: ToolValidationConfig,
+
+    /// Automatic continuation of paused and truncated turns
+    #[serde(default)]
+    pub continuation: ContinuationConfig,
+
+    /// Handling of assistant prefills
+    #[serde(default)]
+    pub prefill: PrefillConfig,
+
+    /// The tool loop run by `RunAgent`
+    #[serde(default)]
+    pub agent: AgentConfig,
 }
 
 impl Default for Config {
@@ -54,6 +120,19 @@ impl Default for Config {
             max_cache_size: Some(100),
             timeout_ms: 30000,  // 30 seconds
             retry_config: RetryConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            budgets: BudgetConfig::default(),
+            usage_ledger: UsageLedgerConfig::default(),
+            context_window: ContextWindowConfig::default(),
+            compaction: CompactionConfig::default(),
+            sessions: SessionConfig::default(),
+            prompt_caching: PromptCachingConfig::default(),
+            normalization: NormalizationConfig::default(),
+            tool_validation: ToolValidationConfig::default(),
+            continuation: ContinuationConfig::default(),
+            prefill: PrefillConfig::default(),
+            agent: AgentConfig::default(),
         }
     }
 }
@@ -72,6 +151,40 @@ pub struct State {
     
     /// Store ID (if using runtime store)
     pub store_id: Option<String>,
+
+    /// Circuit breaker state for the upstream API
+    #[serde(default)]
+    pub circuit: CircuitBreaker,
+
+    /// Token buckets for client-side rate limiting
+    #[serde(default)]
+    pub rate_limiter: RateLimiter,
+
+    /// Upstream rate limits learned from response headers, keyed by model ID
+    #[serde(default)]
+    pub upstream_limits: HashMap<String, UpstreamRateLimits>,
+
+    /// Budget spend per caller
+    #[serde(default)]
+    pub budget_ledger: BudgetLedger,
+
+    /// Record of every completed request
+    #[serde(default)]
+    pub usage_ledger: UsageLedger,
+
+    /// Conversation summaries, capped at `config.max_cache_size` entries. Kept here
+    /// rather than in the store, which the actor cannot reach, so repeated turns of a
+    /// conversation reuse them across requests.
+    #[serde(default)]
+    pub summaries: SummaryCache,
+
+    /// Conversation sessions held for callers
+    #[serde(default)]
+    pub sessions: SessionStore,
+
+    /// Prompt cache effectiveness, keyed by model ID
+    #[serde(default)]
+    pub prompt_cache: HashMap<String, PromptCacheStats>,
 }
 
 impl State {
@@ -86,6 +199,14 @@ impl State {
             api_key,
             config: config.unwrap_or_default(),
             store_id,
+            circuit: CircuitBreaker::default(),
+            rate_limiter: RateLimiter::default(),
+            upstream_limits: HashMap::new(),
+            budget_ledger: BudgetLedger::default(),
+            usage_ledger: UsageLedger::default(),
+            summaries: SummaryCache::default(),
+            sessions: SessionStore::default(),
+            prompt_cache: HashMap::new(),
         }
     }
 }
@@ -0,0 +1,144 @@
+//! Calendar helpers for timestamps in milliseconds since the Unix epoch.
+//!
+//! These use the proleptic Gregorian calendar in UTC.
+
+/// Milliseconds in one day
+pub const DAY_MS: u64 = 86_400_000;
+
+/// Days since the Unix epoch for a civil date
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Civil date `(year, month, day)` for a number of days since the Unix epoch
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Parse an RFC 3339 timestamp (e.g. `2025-01-01T00:00:30Z`) into milliseconds since the Unix epoch
+pub fn parse_rfc3339_ms(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (date, time) = value.split_once(['T', 't', ' '])?;
+
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: i64 = date_parts.next()?.parse().ok()?;
+    let day: i64 = date_parts.next()?.parse().ok()?;
+
+    // Split off the UTC offset
+    let (clock, offset_secs) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, 0)
+    } else {
+        let idx = time.rfind(['+', '-'])?;
+        let (clock, offset) = time.split_at(idx);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        let hours: i64 = hours.parse().ok()?;
+        let minutes: i64 = minutes.parse().ok()?;
+        (clock, sign * (hours * 3600 + minutes * 60))
+    };
+
+    let mut clock_parts = clock.splitn(3, ':');
+    let hour: i64 = clock_parts.next()?.parse().ok()?;
+    let minute: i64 = clock_parts.next()?.parse().ok()?;
+    let seconds = clock_parts.next()?;
+    let (seconds, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+    let seconds: i64 = seconds.parse().ok()?;
+
+    // Only milliseconds are kept; further digits are truncated
+    if !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let millis: i64 = format!("{:0<3}", &fraction[..fraction.len().min(3)])
+        .parse()
+        .ok()?;
+
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86400 + hour * 3600 + minute * 60 + seconds - offset_secs;
+    u64::try_from(secs * 1000 + millis).ok()
+}
+
+/// Format a timestamp's UTC day as `YYYY-MM-DD`
+pub fn format_day(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / DAY_MS) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_utc_timestamps() {
+        assert_eq!(parse_rfc3339_ms("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_rfc3339_ms("2025-01-01T00:00:30Z"),
+            Some(1_735_689_630_000)
+        );
+        // Leap day
+        assert_eq!(
+            parse_rfc3339_ms("2024-02-29T12:00:00Z"),
+            Some(1_709_208_000_000)
+        );
+    }
+
+    #[test]
+    fn parses_fractional_seconds_exactly() {
+        assert_eq!(
+            parse_rfc3339_ms("2025-01-01T00:00:30.5Z"),
+            Some(1_735_689_630_500)
+        );
+        assert_eq!(
+            parse_rfc3339_ms("2025-01-01T00:00:30.029Z"),
+            Some(1_735_689_630_029)
+        );
+        // Sub-millisecond digits are truncated
+        assert_eq!(
+            parse_rfc3339_ms("2025-01-01T00:00:30.123987654Z"),
+            Some(1_735_689_630_123)
+        );
+    }
+
+    #[test]
+    fn applies_utc_offsets() {
+        let utc = parse_rfc3339_ms("2025-01-01T00:00:30Z");
+        assert_eq!(parse_rfc3339_ms("2025-01-01T01:30:30+01:30"), utc);
+        assert_eq!(parse_rfc3339_ms("2024-12-31T19:00:30-05:00"), utc);
+        assert_eq!(parse_rfc3339_ms("2025-01-01 00:00:30+00:00"), utc);
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        for value in [
+            "",
+            "2025-01-01",
+            "2025-01-01T00:00",
+            "2025-01-01T00:00:30",
+            "2025-01-01T00:00:30.1x2Z",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert_eq!(parse_rfc3339_ms(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn formats_days() {
+        assert_eq!(format_day(1_735_689_630_000), "2025-01-01");
+        assert_eq!(format_day(1_709_208_000_000), "2024-02-29");
+    }
+}
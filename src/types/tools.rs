@@ -0,0 +1,466 @@
+use mcp_protocol::tool::Tool;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// The `type`s of `ServerTool`
+const SERVER_TOOL_TYPES: &[&str] = &[
+    "web_search_20250305",
+    "web_fetch_20250910",
+    "code_execution_20250522",
+    "code_execution_20250825",
+];
+
+/// The `type`s of `ClientTool`
+const CLIENT_TOOL_TYPES: &[&str] = &[
+    "bash_20241022",
+    "bash_20250124",
+    "text_editor_20241022",
+    "text_editor_20250124",
+    "text_editor_20250429",
+    "text_editor_20250728",
+    "computer_20241022",
+    "computer_20250124",
+];
+
+/// A tool definition in a completion request
+///
+/// Server and client tools are recognized by their versioned `type`. A tool without a
+/// `type`, or with `type` `custom`, is a custom tool with an `input_schema`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum AnthropicTool {
+    /// A tool Anthropic runs on its side
+    Server(ServerTool),
+
+    /// An Anthropic-defined tool the caller runs, with a schema built into the model
+    Client(ClientTool),
+
+    /// A tool defined by the caller with a JSON Schema for its input
+    Custom(Tool),
+}
+
+impl AnthropicTool {
+    /// Name the model uses to call the tool
+    pub fn name(&self) -> &str {
+        match self {
+            AnthropicTool::Server(tool) => tool.name(),
+            AnthropicTool::Client(tool) => tool.name(),
+            AnthropicTool::Custom(tool) => &tool.name,
+        }
+    }
+
+    /// The `anthropic-beta` header value the tool needs, if any
+    pub fn beta(&self) -> Option<&'static str> {
+        match self {
+            AnthropicTool::Server(tool) => tool.beta(),
+            AnthropicTool::Client(tool) => tool.beta(),
+            AnthropicTool::Custom(_) => None,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AnthropicTool {
+    /// Pick the kind of tool by its `type` first, so a mistyped definition reports what
+    /// is wrong with it instead of failing to match any kind
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let tool_type = match value.get("type") {
+            None => "custom".to_string(),
+            Some(Value::String(tool_type)) => tool_type.clone(),
+            Some(other) => {
+                return Err(D::Error::custom(format!(
+                    "tool type must be a string, got {}",
+                    other
+                )))
+            }
+        };
+
+        let tool = if tool_type == "custom" {
+            Tool::deserialize(value).map(AnthropicTool::Custom)
+        } else if SERVER_TOOL_TYPES.contains(&tool_type.as_str()) {
+            ServerTool::deserialize(value).map(AnthropicTool::Server)
+        } else if CLIENT_TOOL_TYPES.contains(&tool_type.as_str()) {
+            ClientTool::deserialize(value).map(AnthropicTool::Client)
+        } else {
+            return Err(D::Error::custom(format!(
+                "unknown tool type '{}'",
+                tool_type
+            )));
+        };
+        tool.map_err(|e| D::Error::custom(format!("invalid {} tool: {}", tool_type, e)))
+    }
+}
+
+impl From<Tool> for AnthropicTool {
+    fn from(tool: Tool) -> Self {
+        AnthropicTool::Custom(tool)
+    }
+}
+
+/// Server tools, tagged with their versioned type
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum ServerTool {
+    #[serde(rename = "web_search_20250305")]
+    WebSearch20250305(WebSearchTool),
+
+    #[serde(rename = "web_fetch_20250910")]
+    WebFetch20250910(WebFetchTool),
+
+    #[serde(rename = "code_execution_20250522")]
+    CodeExecution20250522(CodeExecutionTool),
+
+    #[serde(rename = "code_execution_20250825")]
+    CodeExecution20250825(CodeExecutionTool),
+}
+
+impl ServerTool {
+    /// Name the model uses to call the tool
+    pub fn name(&self) -> &str {
+        match self {
+            ServerTool::WebSearch20250305(tool) => &tool.name,
+            ServerTool::WebFetch20250910(tool) => &tool.name,
+            ServerTool::CodeExecution20250522(tool) | ServerTool::CodeExecution20250825(tool) => {
+                &tool.name
+            }
+        }
+    }
+
+    /// The `anthropic-beta` header value the tool needs, if any
+    pub fn beta(&self) -> Option<&'static str> {
+        match self {
+            ServerTool::WebSearch20250305(_) => None,
+            ServerTool::WebFetch20250910(_) => Some("web-fetch-2025-09-10"),
+            ServerTool::CodeExecution20250522(_) => Some("code-execution-2025-05-22"),
+            ServerTool::CodeExecution20250825(_) => Some("code-execution-2025-08-25"),
+        }
+    }
+}
+
+/// Web search, billed per search on top of tokens
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSearchTool {
+    #[serde(default = "WebSearchTool::default_name")]
+    pub name: String,
+
+    /// Maximum searches in one request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_uses: Option<u32>,
+
+    /// Only include results from these domains
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_domains: Option<Vec<String>>,
+
+    /// Never include results from these domains
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked_domains: Option<Vec<String>>,
+
+    /// Approximate location used to localize results
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_location: Option<UserLocation>,
+}
+
+impl WebSearchTool {
+    fn default_name() -> String {
+        "web_search".to_string()
+    }
+}
+
+/// Approximate user location for web search
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename = "approximate")]
+pub struct UserLocation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+
+    /// ISO 3166-1 alpha-2 country code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+
+    /// IANA time zone, e.g. "America/New_York"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+/// Web fetch of full page and PDF contents
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebFetchTool {
+    #[serde(default = "WebFetchTool::default_name")]
+    pub name: String,
+
+    /// Maximum fetches in one request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_uses: Option<u32>,
+
+    /// Only fetch from these domains
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_domains: Option<Vec<String>>,
+
+    /// Never fetch from these domains
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked_domains: Option<Vec<String>>,
+
+    /// Whether fetched documents can be cited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub citations: Option<CitationsConfig>,
+
+    /// Truncate fetched content to this many tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_content_tokens: Option<u32>,
+}
+
+impl WebFetchTool {
+    fn default_name() -> String {
+        "web_fetch".to_string()
+    }
+}
+
+/// Citation settings
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CitationsConfig {
+    pub enabled: bool,
+}
+
+/// Code execution in a sandboxed container
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CodeExecutionTool {
+    #[serde(default = "CodeExecutionTool::default_name")]
+    pub name: String,
+}
+
+impl CodeExecutionTool {
+    fn default_name() -> String {
+        "code_execution".to_string()
+    }
+}
+
+/// Schema-less client tools, tagged with their versioned type
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum ClientTool {
+    #[serde(rename = "bash_20241022")]
+    Bash20241022(BashTool),
+
+    #[serde(rename = "bash_20250124")]
+    Bash20250124(BashTool),
+
+    #[serde(rename = "text_editor_20241022")]
+    TextEditor20241022(TextEditorTool),
+
+    #[serde(rename = "text_editor_20250124")]
+    TextEditor20250124(TextEditorTool),
+
+    #[serde(rename = "text_editor_20250429")]
+    TextEditor20250429(TextEditorTool),
+
+    #[serde(rename = "text_editor_20250728")]
+    TextEditor20250728(TextEditorTool),
+
+    #[serde(rename = "computer_20241022")]
+    Computer20241022(ComputerTool),
+
+    #[serde(rename = "computer_20250124")]
+    Computer20250124(ComputerTool),
+}
+
+impl ClientTool {
+    /// Name the model uses to call the tool
+    pub fn name(&self) -> &str {
+        match self {
+            ClientTool::Bash20241022(tool) | ClientTool::Bash20250124(tool) => &tool.name,
+            ClientTool::TextEditor20241022(tool)
+            | ClientTool::TextEditor20250124(tool)
+            | ClientTool::TextEditor20250429(tool)
+            | ClientTool::TextEditor20250728(tool) => &tool.name,
+            ClientTool::Computer20241022(tool) | ClientTool::Computer20250124(tool) => &tool.name,
+        }
+    }
+
+    /// The `anthropic-beta` header value the tool needs, if any
+    pub fn beta(&self) -> Option<&'static str> {
+        match self {
+            ClientTool::Bash20241022(_)
+            | ClientTool::TextEditor20241022(_)
+            | ClientTool::Computer20241022(_) => Some("computer-use-2024-10-22"),
+            ClientTool::Computer20250124(_) => Some("computer-use-2025-01-24"),
+            ClientTool::Bash20250124(_)
+            | ClientTool::TextEditor20250124(_)
+            | ClientTool::TextEditor20250429(_)
+            | ClientTool::TextEditor20250728(_) => None,
+        }
+    }
+
+    /// Tokens the tool adds to the system prompt
+    pub fn system_prompt_tokens(&self) -> u32 {
+        match self {
+            ClientTool::Bash20241022(_) | ClientTool::Bash20250124(_) => 245,
+            ClientTool::TextEditor20241022(_)
+            | ClientTool::TextEditor20250124(_)
+            | ClientTool::TextEditor20250429(_)
+            | ClientTool::TextEditor20250728(_) => 700,
+            ClientTool::Computer20241022(_) | ClientTool::Computer20250124(_) => 735,
+        }
+    }
+}
+
+/// A persistent bash shell
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BashTool {
+    #[serde(default = "BashTool::default_name")]
+    pub name: String,
+}
+
+impl BashTool {
+    fn default_name() -> String {
+        "bash".to_string()
+    }
+}
+
+/// File viewing and editing.
+///
+/// The name depends on the version: `str_replace_editor` up to `text_editor_20250124`,
+/// `str_replace_based_edit_tool` from `text_editor_20250429`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TextEditorTool {
+    pub name: String,
+
+    /// Truncate viewed files to this many characters (`text_editor_20250728` only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_characters: Option<u32>,
+}
+
+/// Screenshots plus mouse and keyboard control of a display
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ComputerTool {
+    #[serde(default = "ComputerTool::default_name")]
+    pub name: String,
+
+    pub display_width_px: u32,
+
+    pub display_height_px: u32,
+
+    /// X11 display number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_number: Option<u32>,
+}
+
+impl ComputerTool {
+    fn default_name() -> String {
+        "computer".to_string()
+    }
+}
+
+/// `anthropic-beta` header value for requests with `mcp_servers`
+pub const MCP_CLIENT_BETA: &str = "mcp-client-2025-04-04";
+
+/// A remote MCP server the API connects to and calls tools on
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename = "url")]
+pub struct McpServer {
+    /// Server URL, which must be HTTPS
+    pub url: String,
+
+    /// Name that identifies the server in `mcp_tool_use` blocks
+    pub name: String,
+
+    /// OAuth access token sent to the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authorization_token: Option<String>,
+
+    /// Which of the server's tools are available
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_configuration: Option<McpToolConfiguration>,
+}
+
+/// Tool access for an MCP server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct McpToolConfiguration {
+    /// Whether the server's tools are available at all
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+
+    /// Only these tools are available; all of them if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_tools: Option<Vec<String>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(value: Value) -> Result<AnthropicTool, String> {
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn tools_are_told_apart_by_type() {
+        for tool_type in SERVER_TOOL_TYPES {
+            let tool = parse(json!({ "type": tool_type })).unwrap();
+            assert!(matches!(tool, AnthropicTool::Server(_)), "{}", tool_type);
+        }
+        for tool_type in CLIENT_TOOL_TYPES {
+            let tool = parse(json!({
+                "type": tool_type,
+                "name": "tool",
+                "display_width_px": 1024,
+                "display_height_px": 768
+            }))
+            .unwrap();
+            assert!(matches!(tool, AnthropicTool::Client(_)), "{}", tool_type);
+        }
+
+        let schema = json!({ "type": "object" });
+        for tool in [
+            json!({ "name": "search", "input_schema": schema }),
+            json!({ "type": "custom", "name": "search", "input_schema": schema }),
+        ] {
+            assert!(matches!(parse(tool).unwrap(), AnthropicTool::Custom(_)));
+        }
+    }
+
+    #[test]
+    fn mistyped_tools_report_what_is_wrong() {
+        assert_eq!(
+            parse(json!({ "type": "computer_20250124", "display_width_px": 1024 })).unwrap_err(),
+            "invalid computer_20250124 tool: missing field `display_height_px`"
+        );
+        assert_eq!(
+            parse(json!({ "type": "web_search_20250305", "max_uses": "five" })).unwrap_err(),
+            "invalid web_search_20250305 tool: invalid type: string \"five\", expected u32"
+        );
+        assert_eq!(
+            parse(json!({ "name": "search" })).unwrap_err(),
+            "invalid custom tool: missing field `input_schema`"
+        );
+    }
+
+    #[test]
+    fn unknown_types_are_named() {
+        assert_eq!(
+            parse(json!({ "type": "web_search_20260101", "name": "web_search" })).unwrap_err(),
+            "unknown tool type 'web_search_20260101'"
+        );
+        assert_eq!(
+            parse(json!({ "type": 3, "name": "web_search" })).unwrap_err(),
+            "tool type must be a string, got 3"
+        );
+    }
+
+    #[test]
+    fn tools_serialize_without_a_wrapper() {
+        let tools = [
+            json!({ "type": "web_search_20250305", "name": "web_search", "max_uses": 5 }),
+            json!({ "type": "bash_20250124", "name": "bash" }),
+            json!({ "name": "search", "input_schema": { "type": "object" } }),
+        ];
+        for tool in tools {
+            let parsed = parse(tool.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), tool);
+        }
+    }
+}
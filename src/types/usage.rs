@@ -0,0 +1,386 @@
+use crate::types::api::{AnthropicCompletionResponse, AnthropicStopReason};
+use crate::types::time::format_day;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Usage ledger configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UsageLedgerConfig {
+    /// Whether completions are recorded in the ledger
+    pub enabled: bool,
+
+    /// Maximum number of records to retain, 10,000 by default, which keeps the ledger
+    /// to a few megabytes of actor state. The oldest records are dropped first and
+    /// queries report that their totals are incomplete. `None` keeps every record.
+    pub max_records: Option<usize>,
+}
+
+impl Default for UsageLedgerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_records: Some(10_000),
+        }
+    }
+}
+
+/// A single completion recorded in the usage ledger
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsageRecord {
+    /// When the completion finished, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+
+    /// Caller key the request was made for
+    pub caller: Option<String>,
+
+    /// Model that generated the completion
+    pub model: String,
+
+    pub input_tokens: u32,
+
+    pub output_tokens: u32,
+
+    pub cache_creation_input_tokens: u32,
+
+    pub cache_read_input_tokens: u32,
+
+    /// Computed cost in dollars
+    pub cost_usd: f64,
+
+    /// Time spent waiting on the API, including retries, in milliseconds
+    pub latency_ms: u64,
+
+    /// Reason generation stopped
+    pub stop_reason: AnthropicStopReason,
+
+    /// Anthropic message ID
+    pub message_id: String,
+
+    /// Anthropic's `request-id` response header
+    pub upstream_request_id: Option<String>,
+}
+
+impl UsageRecord {
+    /// Build a record for a completed request
+    pub fn new(
+        completion: &AnthropicCompletionResponse,
+        caller: Option<&str>,
+        cost_usd: f64,
+        latency_ms: u64,
+        timestamp: u64,
+    ) -> Self {
+        Self {
+            timestamp,
+            caller: caller.map(|c| c.to_string()),
+            model: completion.model.clone(),
+            input_tokens: completion.usage.input_tokens,
+            output_tokens: completion.usage.output_tokens,
+            cache_creation_input_tokens: completion.usage.cache_creation_input_tokens.unwrap_or(0),
+            cache_read_input_tokens: completion.usage.cache_read_input_tokens.unwrap_or(0),
+            cost_usd,
+            latency_ms,
+            stop_reason: completion.stop_reason.clone(),
+            message_id: completion.id.clone(),
+            upstream_request_id: completion.upstream_request_id.clone(),
+        }
+    }
+
+    /// Stop reason as it appears on the wire
+    fn stop_reason_name(&self) -> String {
+        serde_json::to_value(&self.stop_reason)
+            .ok()
+            .and_then(|v| v.as_str().map(|s| s.to_string()))
+            .unwrap_or_default()
+    }
+}
+
+/// How to group records when aggregating
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum UsageGroupBy {
+    Caller,
+
+    Model,
+
+    CallerAndModel,
+
+    /// UTC day, as `YYYY-MM-DD`
+    Day,
+}
+
+/// Filter for querying or exporting the usage ledger
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct UsageQuery {
+    /// Earliest timestamp to include (inclusive), in milliseconds since the Unix epoch
+    pub from: Option<u64>,
+
+    /// Latest timestamp to include (exclusive), in milliseconds since the Unix epoch
+    pub to: Option<u64>,
+
+    /// Only include records for this caller
+    pub caller: Option<String>,
+
+    /// Only include records for this model
+    pub model: Option<String>,
+
+    /// Aggregate the matching records by this key
+    pub group_by: Option<UsageGroupBy>,
+
+    /// Whether to return the matching records alongside the aggregates
+    pub include_records: bool,
+}
+
+impl UsageQuery {
+    fn matches(&self, record: &UsageRecord) -> bool {
+        self.from.is_none_or(|from| record.timestamp >= from)
+            && self.to.is_none_or(|to| record.timestamp < to)
+            && self
+                .caller
+                .as_ref()
+                .is_none_or(|caller| record.caller.as_ref() == Some(caller))
+            && self
+                .model
+                .as_ref()
+                .is_none_or(|model| &record.model == model)
+    }
+}
+
+/// Totals for a group of records
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageAggregate {
+    /// Group key. `total` when the query has no grouping.
+    pub key: String,
+
+    pub requests: u64,
+
+    pub input_tokens: u64,
+
+    pub output_tokens: u64,
+
+    pub cache_creation_input_tokens: u64,
+
+    pub cache_read_input_tokens: u64,
+
+    pub cost_usd: f64,
+
+    /// Mean latency in milliseconds
+    pub average_latency_ms: u64,
+}
+
+impl UsageAggregate {
+    fn add(&mut self, record: &UsageRecord) {
+        // Keep a running mean so totals don't need a separate field
+        let total_latency = self.average_latency_ms * self.requests + record.latency_ms;
+        self.requests += 1;
+        self.average_latency_ms = total_latency / self.requests;
+
+        self.input_tokens += record.input_tokens as u64;
+        self.output_tokens += record.output_tokens as u64;
+        self.cache_creation_input_tokens += record.cache_creation_input_tokens as u64;
+        self.cache_read_input_tokens += record.cache_read_input_tokens as u64;
+        self.cost_usd += record.cost_usd;
+    }
+}
+
+/// Result of a usage query
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsageReport {
+    /// Aggregates per group, sorted by key
+    pub aggregates: Vec<UsageAggregate>,
+
+    /// Matching records, if requested
+    pub records: Option<Vec<UsageRecord>>,
+
+    /// Set when records in the queried range were dropped by `max_records`: the
+    /// timestamp of the newest dropped record. Totals before it are incomplete.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dropped_through: Option<u64>,
+}
+
+/// Export file format
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum UsageExportFormat {
+    Csv,
+
+    Jsonl,
+}
+
+/// Append-only ledger of completed requests
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UsageLedger {
+    pub records: Vec<UsageRecord>,
+
+    /// Number of records dropped to stay within `max_records`
+    #[serde(default)]
+    pub dropped_records: u64,
+
+    /// Timestamp of the newest dropped record
+    #[serde(default)]
+    pub dropped_through: Option<u64>,
+}
+
+impl UsageLedger {
+    /// Append a record, dropping the oldest records beyond `max_records`.
+    ///
+    /// Returns how many records were dropped.
+    pub fn append(&mut self, record: UsageRecord, max_records: Option<usize>) -> usize {
+        self.records.push(record);
+
+        let excess = max_records.map_or(0, |max| self.records.len().saturating_sub(max));
+        for dropped in self.records.drain(..excess) {
+            self.dropped_records += 1;
+            self.dropped_through = self.dropped_through.max(Some(dropped.timestamp));
+        }
+        excess
+    }
+
+    /// Aggregate the records matching a query
+    pub fn query(&self, query: &UsageQuery) -> UsageReport {
+        let mut groups: BTreeMap<String, UsageAggregate> = BTreeMap::new();
+        let mut records = Vec::new();
+
+        for record in self.records.iter().filter(|r| query.matches(r)) {
+            let key = group_key(record, query.group_by);
+            groups
+                .entry(key.clone())
+                .or_insert_with(|| UsageAggregate {
+                    key,
+                    ..Default::default()
+                })
+                .add(record);
+
+            if query.include_records {
+                records.push(record.clone());
+            }
+        }
+
+        UsageReport {
+            aggregates: groups.into_values().collect(),
+            records: query.include_records.then_some(records),
+            dropped_through: self
+                .dropped_through
+                .filter(|&through| query.from.is_none_or(|from| from <= through)),
+        }
+    }
+
+    /// Export the records matching a query
+    pub fn export(&self, query: &UsageQuery, format: UsageExportFormat) -> Result<String, String> {
+        let records = self.records.iter().filter(|r| query.matches(r));
+        let mut out = String::new();
+
+        match format {
+            UsageExportFormat::Csv => {
+                out.push_str(
+                    "timestamp,caller,model,input_tokens,output_tokens,\
+                     cache_creation_input_tokens,cache_read_input_tokens,cost_usd,\
+                     latency_ms,stop_reason,message_id,upstream_request_id\n",
+                );
+                for record in records {
+                    out.push_str(&format!(
+                        "{},{},{},{},{},{},{},{:.6},{},{},{},{}\n",
+                        record.timestamp,
+                        csv_field(record.caller.as_deref().unwrap_or_default()),
+                        csv_field(&record.model),
+                        record.input_tokens,
+                        record.output_tokens,
+                        record.cache_creation_input_tokens,
+                        record.cache_read_input_tokens,
+                        record.cost_usd,
+                        record.latency_ms,
+                        record.stop_reason_name(),
+                        csv_field(&record.message_id),
+                        csv_field(record.upstream_request_id.as_deref().unwrap_or_default()),
+                    ));
+                }
+            }
+            UsageExportFormat::Jsonl => {
+                for record in records {
+                    let line = serde_json::to_string(record).map_err(|e| e.to_string())?;
+                    out.push_str(&line);
+                    out.push('\n');
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+fn group_key(record: &UsageRecord, group_by: Option<UsageGroupBy>) -> String {
+    let caller = || record.caller.clone().unwrap_or_else(|| "-".to_string());
+
+    match group_by {
+        None => "total".to_string(),
+        Some(UsageGroupBy::Caller) => caller(),
+        Some(UsageGroupBy::Model) => record.model.clone(),
+        Some(UsageGroupBy::CallerAndModel) => format!("{}/{}", caller(), record.model),
+        Some(UsageGroupBy::Day) => format_day(record.timestamp),
+    }
+}
+
+/// Quote a CSV field if it contains separators, quotes or newlines
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: u64) -> UsageRecord {
+        UsageRecord {
+            timestamp,
+            caller: Some("tenant-a".to_string()),
+            model: "claude-sonnet-4-5-20250929".to_string(),
+            input_tokens: 100,
+            output_tokens: 10,
+            cache_creation_input_tokens: 0,
+            cache_read_input_tokens: 0,
+            cost_usd: 0.00045,
+            latency_ms: 500,
+            stop_reason: AnthropicStopReason::EndTurn,
+            message_id: format!("msg_{}", timestamp),
+            upstream_request_id: None,
+        }
+    }
+
+    #[test]
+    fn records_are_kept_without_a_cap() {
+        let mut ledger = UsageLedger::default();
+        for timestamp in 0..5 {
+            assert_eq!(ledger.append(record(timestamp), None), 0);
+        }
+
+        let report = ledger.query(&UsageQuery::default());
+        assert_eq!(report.aggregates[0].requests, 5);
+        assert_eq!(report.dropped_through, None);
+    }
+
+    #[test]
+    fn dropped_records_are_reported() {
+        let mut ledger = UsageLedger::default();
+        for timestamp in 0..3 {
+            ledger.append(record(timestamp), Some(3));
+        }
+        assert_eq!(ledger.append(record(3), Some(3)), 1);
+        assert_eq!(ledger.append(record(4), Some(3)), 1);
+        assert_eq!(ledger.records.len(), 3);
+        assert_eq!(ledger.dropped_records, 2);
+
+        // Queries reaching back to the dropped records say their totals are incomplete
+        let report = ledger.query(&UsageQuery::default());
+        assert_eq!(report.aggregates[0].requests, 3);
+        assert_eq!(report.dropped_through, Some(1));
+
+        let recent = UsageQuery {
+            from: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(ledger.query(&recent).dropped_through, None);
+    }
+}